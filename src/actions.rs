@@ -1,113 +1,538 @@
 use std::{
-    fs::OpenOptions, io::ErrorKind, net::SocketAddr, path::PathBuf, sync::{Arc, atomic::{AtomicU64, AtomicBool, Ordering::SeqCst}}, time::SystemTime,
+    fs::OpenOptions, io::ErrorKind, net::SocketAddr, path::{Path, PathBuf}, sync::{Arc, Mutex, atomic::{AtomicU64, AtomicBool, Ordering::SeqCst}}, time::Duration,
 };
 
 use axum::{
-    extract::{ConnectInfo, Multipart, State},
-    http::StatusCode,
-    Extension, Form,
+    extract::{ConnectInfo, FromRequestParts, Multipart, State},
+    http::{header::CONTENT_LENGTH, HeaderMap, StatusCode},
+    response::Result,
+    Extension, Form, Json,
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use tokio::io::AsyncWriteExt;
 use tokio_util::codec::{FramedWrite, BytesCodec};
-use tracing::{warn, error};
-use futures::{stream::StreamExt, SinkExt, TryStreamExt};
+use tracing::{info, warn, error};
+use futures::{stream::StreamExt, SinkExt, Stream, TryStreamExt};
 
-use crate::{disksize::Quotas, SharedDirectory};
+use crate::{audit::Event, bandwidth::{Bandwidth, Direction}, clientlimits::{ClientLimits, Throttled}, disksize::Quotas, filename, highlight, meta, ownership::Ownership, symlinks, tls::ClientIdentity, SharedDirectory};
 
-fn allowed_filename(x: &str) -> bool {
-    if x.contains("..") {
-        return false;
+/// Uploads are received into hidden `.NAME.N.uploading` files and renamed into place when complete
+const STAGING_SUFFIX: &str = ".uploading";
+
+/// Slack for multipart headers and boundaries when comparing Content-Length with maximum file size
+const MULTIPART_OVERHEAD: u64 = 16 * 1024;
+
+/// Whether `x` can refer to an existing file directly in a shared directory
+pub(crate) fn allowed_filename(x: &str) -> bool {
+    !x.is_empty() && x != "." && x != ".." && !x.contains(['/', '\0'])
+}
+
+/// Client and shared directory of a request that uploads, shares, edits or removes files
+#[derive(FromRequestParts)]
+pub(crate) struct Writer {
+    #[from_request(via(ConnectInfo))]
+    addr: SocketAddr,
+    #[from_request(via(Extension))]
+    identity: ClientIdentity,
+    #[from_request(via(Extension))]
+    shared_dir: Arc<SharedDirectory>,
+    #[from_request(via(Extension))]
+    client_limits: Arc<ClientLimits>,
+}
+
+/// Where a text share or uploaded file ended up
+#[derive(Serialize)]
+pub(crate) struct Stored {
+    original_name: String,
+    /// Name in the shared directory after sanitization and collision handling
+    name: String,
+}
+
+/// What to do when a file with the same name already exists
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OnConflict {
+    /// Add ` (1)`, ` (2)`, ... before the extension
+    Rename,
+    /// Replace the old file
+    Overwrite,
+    /// Refuse the upload with `409 Conflict`
+    Reject,
+    /// Replace the old file, keeping it in the hidden history like previous versions of edited texts
+    History,
+}
+
+pub fn parse_on_conflict(x: &str) -> std::result::Result<OnConflict, String> {
+    match x {
+        "rename" => Ok(OnConflict::Rename),
+        "overwrite" => Ok(OnConflict::Overwrite),
+        "reject" => Ok(OnConflict::Reject),
+        "history" => Ok(OnConflict::History),
+        _ => Err(format!("Unknown collision strategy `{x}`, expected `rename`, `overwrite`, `reject` or `history`")),
     }
-    if x.contains('/') {
-        return false;
+}
+
+/// Inner extensions that are kept together with the last one, like `.tar.gz`
+const COMPOUND_EXTENSIONS: &[&str] = &["tar"];
+
+/// `photo.jpg` becomes `photo (N).jpg` and `archive.tar.gz` becomes `archive (N).tar.gz`;
+/// names without extension get the number at the end
+fn numbered_name(filename: &str, n: u64) -> String {
+    let Some((stem, ext)) = filename.rsplit_once('.').filter(|(stem, _)| !stem.is_empty()) else {
+        return format!("{filename} ({n})");
+    };
+    match stem.rsplit_once('.') {
+        Some((inner_stem, inner)) if !inner_stem.is_empty() && COMPOUND_EXTENSIONS.iter().any(|x| x.eq_ignore_ascii_case(inner)) => {
+            format!("{inner_stem} ({n}).{inner}.{ext}")
+        }
+        _ => format!("{stem} ({n}).{ext}"),
     }
-    true
 }
 
-fn easy_ts() -> impl std::fmt::Display {
-    SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .map(|x| x.as_secs())
-        .unwrap_or(0)
+/// Move current content of `path` to the hidden history, returning its metadata with the version bumped
+fn move_to_history(path: &Path) -> std::io::Result<meta::FileMeta> {
+    let mut file_meta = meta::load(path);
+    let version = file_meta.current_version();
+    let history = meta::version_path(path, version).ok_or(ErrorKind::InvalidInput)?;
+    std::fs::rename(path, history)?;
+    file_meta.version = version + 1;
+    Ok(file_meta)
+}
+
+fn is_staging_name(x: &str) -> bool {
+    x.starts_with('.') && x.ends_with(STAGING_SUFFIX)
+}
+
+/// Hidden file an upload is being received into. Removed (with quota counters updated) on drop unless published.
+struct StagingFile {
+    path: PathBuf,
+    quotas: Arc<Quotas>,
+    /// Bytes of this file accounted in the quota counter so far
+    accounted: Arc<AtomicU64>,
+    published: bool,
 }
 
-/// Create new file, renaming it if already exists. Also bumps the files quota counter.
-fn create_new_file(dir: &::std::path::Path, filename: &str, quotas: &Quotas) -> Result<(std::fs::File, PathBuf), StatusCode> {
-    if quotas.files.bump(1) {
-        quotas.files.reduce(1);
-        return Err(StatusCode::PAYLOAD_TOO_LARGE);
-    }
-    let mut infix = String::new();
-    for i in 1..=20 {
-        let path = dir.join(format!("{filename}{infix}"));
-        match OpenOptions::new()
-            .read(false)
-            .write(true)
-            .create_new(true)
-            .open(&path)
-        {
-            Ok(f) => return Ok((f, path)),
-            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
-                infix = format!(".{i}");
+impl StagingFile {
+    /// Create new staging file for `filename`. Also bumps the files quota counter.
+    fn create(dir: &Path, filename: &str, ownership: &Ownership, quotas: &Arc<Quotas>) -> std::result::Result<(std::fs::File, StagingFile), StatusCode> {
+        static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+        if quotas.files.bump(1) {
+            quotas.files.reduce(1);
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        let path = dir.join(format!(".{filename}.{}{STAGING_SUFFIX}", SEQUENCE.fetch_add(1, SeqCst)));
+        let file = OpenOptions::new().write(true).create_new(true).open(&path);
+        let file = file.and_then(|f| match ownership.apply(&f) {
+            Ok(()) => Ok(f),
+            Err(e) => {
+                let _ = std::fs::remove_file(&path);
+                Err(e)
             }
+        });
+        match file {
+            Ok(f) => Ok((
+                f,
+                StagingFile {
+                    path,
+                    quotas: quotas.clone(),
+                    accounted: Arc::new(AtomicU64::new(0)),
+                    published: false,
+                },
+            )),
             Err(e) => {
                 quotas.files.reduce(1);
-                warn!("Cannot create new file `{path:?}`: {e}");
+                warn!("Cannot create staging file `{path:?}`: {e}");
+                Err(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+
+    /// Move the file to `filename` + `suffix`, resolving name collision according to `on_conflict`
+    fn publish(mut self, dir: &Path, filename: &str, suffix: &str, on_conflict: OnConflict) -> std::result::Result<PathBuf, StatusCode> {
+        let path = dir.join(format!("{filename}{suffix}"));
+        let result = match on_conflict {
+            OnConflict::Rename | OnConflict::Reject => renamore::rename_exclusive(&self.path, &path),
+            OnConflict::Overwrite => {
+                match std::fs::symlink_metadata(&path) {
+                    Ok(m) if !m.is_file() => return Err(StatusCode::CONFLICT),
+                    Ok(m) => std::fs::rename(&self.path, &path).map(|()| self.quotas.forget_file(&path, &m)),
+                    Err(_) => renamore::rename_exclusive(&self.path, &path),
+                }
+            }
+            OnConflict::History => {
+                let _edit = EDITS.lock().unwrap();
+                match std::fs::symlink_metadata(&path) {
+                    Ok(m) if !m.is_file() => return Err(StatusCode::CONFLICT),
+                    Ok(_) => move_to_history(&path).and_then(|mut file_meta| {
+                        if let Err(e) = std::fs::rename(&self.path, &path) {
+                            // Put the old version back, it is still the current one
+                            let restored = meta::version_path(&path, file_meta.version - 1).map(|history| std::fs::rename(history, &path));
+                            if let Some(Err(e)) = restored {
+                                error!("Cannot restore {path:?} from history: {e}");
+                            }
+                            return Err(e);
+                        }
+                        // Download limit was for the old content
+                        file_meta.max_downloads = None;
+                        file_meta.downloads = 0;
+                        if let Err(e) = meta::save(&path, &file_meta, &self.quotas) {
+                            warn!("Cannot save metadata of {path:?}: {e}");
+                        }
+                        Ok(())
+                    }),
+                    Err(_) => renamore::rename_exclusive(&self.path, &path),
+                }
+            }
+        };
+        match result {
+            Ok(()) => return Ok(self.published_as(path)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists && on_conflict != OnConflict::Reject => (),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => return Err(StatusCode::CONFLICT),
+            Err(e) => {
+                warn!("Cannot rename `{:?}` to `{path:?}`: {e}", self.path);
                 return Err(StatusCode::INTERNAL_SERVER_ERROR);
             }
         }
+        for n in 1.. {
+            let path = dir.join(format!("{}{suffix}", numbered_name(filename, n)));
+            match renamore::rename_exclusive(&self.path, &path) {
+                Ok(()) => return Ok(self.published_as(path)),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => (),
+                Err(e) => {
+                    warn!("Cannot rename `{:?}` to `{path:?}`: {e}", self.path);
+                    return Err(StatusCode::INTERNAL_SERVER_ERROR);
+                }
+            }
+        }
+        unreachable!()
+    }
+
+    /// Atomically replace existing file at `path` with the staged content
+    fn replace(mut self, path: &Path) -> std::io::Result<()> {
+        std::fs::rename(&self.path, path)?;
+        self.published_as(path.to_owned());
+        Ok(())
+    }
+
+    fn published_as(&mut self, path: PathBuf) -> PathBuf {
+        self.published = true;
+        settle_file_size(&self.quotas, &path, self.accounted.load(SeqCst));
+        path
     }
-    quotas.files.reduce(1);
-    Err(StatusCode::CONFLICT)
+}
+
+impl Drop for StagingFile {
+    fn drop(&mut self) {
+        if self.published {
+            return;
+        }
+        match std::fs::remove_file(&self.path) {
+            Ok(()) => {
+                self.quotas.files.reduce(1);
+                self.quotas.bytes.reduce(self.accounted.load(SeqCst));
+            }
+            Err(e) => warn!("Failed to remove staging file {:?}: {e}", self.path),
+        }
+    }
+}
+
+/// Remove staging files left from uploads interrupted by shutdown or crash. Quota counters are not updated.
+pub fn remove_staging_files(dir: &Path) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("Failed to look for incomplete uploads in {dir:?}: {e}");
+            return;
+        }
+    };
+    for entry in entries.flatten() {
+        if !entry.file_name().to_str().is_some_and(is_staging_name) {
+            continue;
+        }
+        match std::fs::remove_file(entry.path()) {
+            Ok(()) => info!("Removed incomplete upload {:?}", entry.path()),
+            Err(e) => warn!("Failed to remove incomplete upload {:?}: {e}", entry.path()),
+        }
+    }
+}
+
+/// Name of a file in shared directory, for audit log
+fn stored_name(path: &Path) -> Option<&str> {
+    path.file_name().and_then(|x| x.to_str())
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Correct byte quota counter after writing a file of which `accounted` bytes are already counted
+fn settle_file_size(quotas: &Quotas, path: &std::path::Path, accounted: u64) {
+    if let Ok(meta) = std::fs::metadata(path) {
+        let actual = quotas.file_size(&meta);
+        if actual > accounted {
+            quotas.bytes.bump(actual - accounted);
+        }
+        if accounted > actual {
+            quotas.bytes.reduce(accounted - actual);
+        }
+    }
+}
+
+/// Fail the stream if its next item does not arrive within `timeout`
+fn with_read_timeout<T, S: Stream<Item = anyhow::Result<T>> + Unpin>(stream: S, timeout: Option<Duration>) -> impl Stream<Item = anyhow::Result<T>> {
+    futures::stream::unfold(Some(stream), move |s| async move {
+        let mut s = s?;
+        let Some(timeout) = timeout else {
+            return s.next().await.map(|x| (x, Some(s)));
+        };
+        match tokio::time::timeout(timeout, s.next()).await {
+            Ok(x) => x.map(|x| (x, Some(s))),
+            Err(_) => {
+                crate::metrics::timed_out(crate::metrics::Timeout::Body);
+                Some((Err(anyhow::anyhow!("Timed out waiting for request body")), None))
+            }
+        }
+    })
 }
 
 #[derive(Deserialize)]
 pub(crate) struct ShareText {
     title: String,
     body: String,
+    /// Syntax highlighting language; detected from title and content if empty
+    #[serde(default)]
+    language: String,
+    /// Delete the text after this many downloads or views; empty means no limit
+    #[serde(default, rename = "maxDownloads")]
+    max_downloads: String,
+}
+
+/// Parse download limit form field: empty or `0` means no limit
+fn parse_max_downloads(x: &str) -> std::result::Result<Option<u32>, StatusCode> {
+    match x.trim() {
+        "" | "0" => Ok(None),
+        x => x.parse().map(Some).map_err(|_| StatusCode::BAD_REQUEST),
+    }
 }
 
 #[axum::debug_handler]
 pub(crate) async fn share_text(
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
-    Extension(shared_dir): Extension<Arc<SharedDirectory>>,
+    Writer {
+        addr,
+        identity,
+        shared_dir,
+        client_limits,
+    }: Writer,
     State(quotas): State<Arc<Quotas>>,
     Form(f): Form<ShareText>,
-) -> Result<(), StatusCode> {
-    println!(
-        "{} {} share_text {} len={}",
-        easy_ts(),
-        addr,
-        f.title,
-        f.body.len()
-    );
-
-    let mut filename = f.title;
-
-    if !allowed_filename(&filename) {
-        return Err(StatusCode::BAD_REQUEST);
+) -> Result<Json<Stored>> {
+    let event = Event {
+        original_name: Some(&f.title),
+        size: Some(f.body.len() as u64),
+        ..Event::request("share_text", shared_dir.name, addr, &identity)
+    };
+    if let Err(e) = shared_dir.write_access.check(&identity) {
+        event.record("forbidden");
+        return Err(e.into());
     }
+    client_limits.request(addr.ip()).map_err(|e| event.rejected(e))?;
+
+    let mut filename = filename::sanitize(&f.title);
+    let language = if f.language.is_empty() {
+        highlight::detect_language(&f.title, &f.body)
+    } else if highlight::is_known_language(&f.language) {
+        Some(f.language)
+    } else {
+        event.record("bad_request");
+        return Err((StatusCode::BAD_REQUEST, "Unknown language").into());
+    };
+    let max_downloads = parse_max_downloads(&f.max_downloads).map_err(|e| event.rejected(e))?;
     if !filename.ends_with(".txt") {
         filename += ".txt";
     }
 
     let body = f.body.into_bytes();
+    if shared_dir.max_file_size.is_some_and(|max| body.len() as u64 > max) {
+        event.record("too_large");
+        return Err((StatusCode::PAYLOAD_TOO_LARGE, "Text is too large").into());
+    }
+    client_limits.check_file(addr.ip(), body.len() as u64).map_err(|e| event.rejected(e))?;
     if quotas.bytes.bump(body.len() as u64) {
         quotas.bytes.reduce(body.len() as u64);
-        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        event.record("quota_exceeded");
+        return Err(StatusCode::PAYLOAD_TOO_LARGE.into());
+    }
+    if !quotas.free_space_check(&shared_dir.dir).consume(body.len() as u64) {
+        quotas.bytes.reduce(body.len() as u64);
+        event.record("disk_full");
+        return Err(StatusCode::INSUFFICIENT_STORAGE.into());
+    }
+    // Only texts that are going to be stored count towards client limits
+    if let Err(e) = client_limits.file(addr.ip()).and_then(|()| client_limits.bytes(addr.ip(), body.len() as u64)) {
+        quotas.bytes.reduce(body.len() as u64);
+        return Err(event.rejected(e).into());
     }
-    let (newfile, _) = create_new_file(&shared_dir.dir, &filename, &quotas)?;
+    let (newfile, staging) = match StagingFile::create(&shared_dir.dir, &filename, &shared_dir.ownership, &quotas) {
+        Ok(x) => x,
+        Err(code) => {
+            quotas.bytes.reduce(body.len() as u64);
+            return Err(event.rejected(code).into());
+        }
+    };
+    staging.accounted.store(body.len() as u64, SeqCst);
     let mut newfile = tokio::fs::File::from_std(newfile);
 
-    match newfile.write_all(&body).await {
-        Ok(()) => (),
-        Err(e) => {
-            warn!("share_text: {e}");
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    // Tokio file may still be writing in background after `write_all`; size is settled after publishing
+    if let Err(e) = async { newfile.write_all(&body).await?; newfile.flush().await }.await {
+        warn!("share_text: {e}");
+        event.record("failed");
+        return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+    }
+    drop(newfile);
+    let newname = staging
+        .publish(&shared_dir.dir, &filename, "", shared_dir.on_conflict)
+        .map_err(|e| event.rejected(e))?;
+    save_meta(&newname, language, max_downloads, &quotas);
+    Event {
+        stored_name: stored_name(&newname),
+        sha256: Some(&sha256_hex(&body)),
+        ..event
+    }
+    .record("ok");
+
+    Ok(Json(Stored {
+        original_name: f.title.clone(),
+        name: stored_name(&newname).unwrap_or_default().to_owned(),
+    }))
+}
+
+/// Record language and download limit of a newly published file, keeping history of the file it replaced if any
+fn save_meta(path: &Path, language: Option<String>, max_downloads: Option<u32>, quotas: &Quotas) {
+    if language.is_none() && max_downloads.is_none() {
+        return;
+    }
+    let mut file_meta = meta::load(path);
+    file_meta.language = language.or(file_meta.language);
+    file_meta.max_downloads = max_downloads;
+    file_meta.downloads = 0;
+    if let Err(e) = meta::save(path, &file_meta, quotas) {
+        warn!("Saving metadata of {path:?}: {e}");
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct EditText {
+    #[serde(rename = "fileName")]
+    filename: String,
+    body: String,
+    /// New syntax highlighting language; the old one is kept if empty
+    #[serde(default)]
+    language: String,
+}
+
+/// Serializes edits, so that concurrent ones do not lose versions
+static EDITS: Mutex<()> = Mutex::new(());
+
+/// Replace content of a text share, moving the previous version to the hidden history
+#[axum::debug_handler]
+pub(crate) async fn edit_text(
+    Writer {
+        addr,
+        identity,
+        shared_dir,
+        client_limits,
+    }: Writer,
+    State(quotas): State<Arc<Quotas>>,
+    Form(f): Form<EditText>,
+) -> Result<()> {
+    let event = Event {
+        original_name: Some(&f.filename),
+        stored_name: Some(&f.filename),
+        size: Some(f.body.len() as u64),
+        ..Event::request("edit_text", shared_dir.name, addr, &identity)
+    };
+    if let Err(e) = shared_dir.write_access.check(&identity) {
+        event.record("forbidden");
+        return Err(e.into());
+    }
+    client_limits.request(addr.ip()).map_err(|e| event.rejected(e))?;
+
+    if !allowed_filename(&f.filename) || f.filename.starts_with('.') || !f.filename.ends_with(".txt") {
+        event.record("bad_name");
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+    if !f.language.is_empty() && !highlight::is_known_language(&f.language) {
+        event.record("bad_request");
+        return Err((StatusCode::BAD_REQUEST, "Unknown language").into());
+    }
+
+    let body = f.body.into_bytes();
+    if shared_dir.max_file_size.is_some_and(|max| body.len() as u64 > max) {
+        event.record("too_large");
+        return Err((StatusCode::PAYLOAD_TOO_LARGE, "Text is too large").into());
+    }
+    client_limits.bytes(addr.ip(), body.len() as u64).map_err(|e| event.rejected(e))?;
+    if quotas.bytes.bump(body.len() as u64) {
+        quotas.bytes.reduce(body.len() as u64);
+        event.record("quota_exceeded");
+        return Err(StatusCode::PAYLOAD_TOO_LARGE.into());
+    }
+    if !quotas.free_space_check(&shared_dir.dir).consume(body.len() as u64) {
+        quotas.bytes.reduce(body.len() as u64);
+        event.record("disk_full");
+        return Err(StatusCode::INSUFFICIENT_STORAGE.into());
+    }
+    // Also accounts the file, as the previous version stays as a separate file
+    let (mut file, staging) = match StagingFile::create(&shared_dir.dir, &f.filename, &shared_dir.ownership, &quotas) {
+        Ok(x) => x,
+        Err(code) => {
+            quotas.bytes.reduce(body.len() as u64);
+            return Err(event.rejected(code).into());
         }
+    };
+    staging.accounted.store(body.len() as u64, SeqCst);
+    if let Err(e) = std::io::Write::write_all(&mut file, &body) {
+        warn!("edit_text: {e}");
+        event.record("failed");
+        return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
     }
+    drop(file);
 
+    let path = shared_dir.dir.join(&f.filename);
+    let _edit = EDITS.lock().unwrap();
+    if !std::fs::symlink_metadata(&path).is_ok_and(|m| m.is_file()) {
+        event.record("not_found");
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+    let mut file_meta = meta::load(&path);
+    // Its content could not be loaded for editing without counting a download
+    if file_meta.max_downloads.is_some() {
+        event.record("download_limited");
+        return Err((StatusCode::CONFLICT, "Texts with a download limit cannot be edited").into());
+    }
+    let version = file_meta.current_version();
+    // Current content gets a second name in the history, then the new one replaces it, so readers see either of them
+    let replaced = meta::version_path(&path, version).ok_or_else(|| ErrorKind::InvalidInput.into()).and_then(|history| {
+        std::fs::hard_link(&path, &history)?;
+        staging.replace(&path).inspect_err(|_| _ = std::fs::remove_file(&history))
+    });
+    if let Err(e) = replaced {
+        warn!("edit_text: replacing {path:?}: {e}");
+        event.record("failed");
+        return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+    }
+    file_meta.version = version + 1;
+    if !f.language.is_empty() {
+        file_meta.language = Some(f.language);
+    }
+    if let Err(e) = meta::save(&path, &file_meta, &quotas) {
+        warn!("edit_text: saving metadata: {e}");
+    }
+    Event {
+        sha256: Some(&sha256_hex(&body)),
+        ..event
+    }
+    .record("ok");
     Ok(())
 }
 
@@ -119,34 +544,50 @@ pub(crate) struct Remove {
 
 #[axum::debug_handler]
 pub(crate) async fn remove(
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
-    Extension(shared_dir): Extension<Arc<SharedDirectory>>,
+    Writer {
+        addr,
+        identity,
+        shared_dir,
+        client_limits,
+    }: Writer,
     State(quotas): State<Arc<Quotas>>,
     Form(f): Form<Remove>,
-) -> Result<(), StatusCode> {
-    println!("{} {} remove {}", easy_ts(), addr, f.filename,);
+) -> Result<()> {
+    let event = Event {
+        original_name: Some(&f.filename),
+        stored_name: Some(&f.filename),
+        ..Event::request("remove", shared_dir.name, addr, &identity)
+    };
+    if let Err(e) = shared_dir.write_access.check(&identity) {
+        event.record("forbidden");
+        return Err(e.into());
+    }
+    client_limits.request(addr.ip()).map_err(|e| event.rejected(e))?;
 
-    if !allowed_filename(&f.filename) {
-        return Err(StatusCode::BAD_REQUEST);
+    if !allowed_filename(&f.filename) || is_staging_name(&f.filename) || meta::is_meta_name(&f.filename) {
+        event.record("bad_name");
+        return Err(StatusCode::BAD_REQUEST.into());
     }
 
-    let p = shared_dir.dir.join(f.filename);
-    let metadata = std::fs::metadata(&p);
-    match std::fs::remove_file(p) {
-        Ok(()) => {
-            quotas.files.reduce(1);
-            match metadata {
-                Ok(m) => {
-                    quotas.bytes.reduce(m.len());
-                },
-                Err(e) => {
-                    error!("pre-remove metadata was failed: {e}");
-                }
-            }
+    if !symlinks::is_allowed(&shared_dir.dir, Path::new(&f.filename), shared_dir.symlinks) {
+        event.record("not_found");
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+
+    match quotas.remove_file(&shared_dir.dir.join(&f.filename)) {
+        Ok(m) => Event {
+            size: Some(m.len()),
+            ..event
+        }
+        .record("ok"),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            event.record("not_found");
+            return Err(StatusCode::NOT_FOUND.into());
         }
         Err(e) => {
             warn!("remove: {e}");
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            event.record("failed");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
         }
     }
 
@@ -155,102 +596,224 @@ pub(crate) async fn remove(
 
 #[axum::debug_handler]
 pub(crate) async fn upload(
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
-    Extension(shared_dir): Extension<Arc<SharedDirectory>>,
+    Writer {
+        addr,
+        identity,
+        shared_dir,
+        client_limits,
+    }: Writer,
+    Extension(bandwidth): Extension<Arc<Bandwidth>>,
     State(quotas): State<Arc<Quotas>>,
+    headers: HeaderMap,
     mut multipart: Multipart,
-) -> Result<(), (StatusCode, &'static str)> {
+) -> Result<Json<Vec<Stored>>> {
+    let event = Event::request("upload_start", shared_dir.name, addr, &identity);
+    if let Err(e) = shared_dir.write_access.check(&identity) {
+        event.record("forbidden");
+        return Err(e.into());
+    }
+    client_limits.request(addr.ip()).map_err(|e| event.rejected(e))?;
+    let _upload_slot = client_limits.upload_slot(addr.ip()).map_err(|e| event.rejected(e))?;
+    let _in_flight = crate::metrics::InFlightUpload::new();
+
+    // Refuse obviously unfitting uploads before receiving the body and book quota for the rest
+    let content_length = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    let mut reservation = None;
+    if let Some(len) = content_length {
+        let event = Event { size: Some(len), ..event };
+        if shared_dir.max_file_size.is_some_and(|max| len > max.saturating_add(MULTIPART_OVERHEAD)) {
+            event.record("too_large");
+            return Err((StatusCode::PAYLOAD_TOO_LARGE, "File is too large").into());
+        }
+        let Some(r) = quotas.reserve_bytes(len) else {
+            event.record("quota_exceeded");
+            return Err((StatusCode::PAYLOAD_TOO_LARGE, "Disk storage quota full").into());
+        };
+        if !quotas.free_space_check(&shared_dir.dir).consume(len) {
+            event.record("disk_full");
+            return Err((StatusCode::INSUFFICIENT_STORAGE, "Not enough free disk space").into());
+        }
+        reservation = Some(Arc::new(r));
+    }
+    let mut max_downloads = None;
+    let mut stored = vec![];
     loop {
         match multipart.next_field().await {
-            Err(_e) => return Err((StatusCode::BAD_REQUEST, "Failed to read multipart")),
+            Err(_e) => return Err((StatusCode::BAD_REQUEST, "Failed to read multipart").into()),
             Ok(None) => break,
             Ok(Some(field)) => {
-                let Some(filename) = field.file_name() else { continue };
-                let filename =filename.to_owned();
+                // Options for the files that follow
+                if field.file_name().is_none() && field.name() == Some("maxDownloads") {
+                    let value = field.text().await.map_err(|_| StatusCode::BAD_REQUEST)?;
+                    max_downloads = parse_max_downloads(&value).map_err(|e| event.rejected(e))?;
+                    continue;
+                }
+                let Some(original_name) = field.file_name() else { continue };
+                let original_name = original_name.to_owned();
+                let filename = filename::sanitize(&original_name);
 
-                println!("{} {} upload {}", easy_ts(), addr, filename);
+                let start = Event {
+                    original_name: Some(&original_name),
+                    ..event
+                };
+                let event = Event { action: "upload", size: None, ..start };
 
-                if !allowed_filename(&filename) {
-                    return Err((StatusCode::BAD_REQUEST, "This filename is not allowed"));
+                if quotas.bytes.is_exceed() {
+                    event.record("quota_exceeded");
+                    return Err((StatusCode::PAYLOAD_TOO_LARGE, "Disk storage quota full").into());
                 }
 
-                if quotas.bytes.is_exceed() {
-                    return Err((StatusCode::PAYLOAD_TOO_LARGE, "Disk storage quota full"));
+                let mut free_space = quotas.free_space_check(&shared_dir.dir);
+                if free_space.is_exhausted() {
+                    event.record("disk_full");
+                    return Err((StatusCode::INSUFFICIENT_STORAGE, "Not enough free disk space").into());
                 }
 
-                let (file, newname) = match create_new_file(&shared_dir.dir, &filename, &quotas) {
+                client_limits.file(addr.ip()).map_err(|e| event.rejected(e))?;
+
+                let (file, staging) = match StagingFile::create(&shared_dir.dir, &filename, &shared_dir.ownership, &quotas) {
                     Ok(x) => x,
                     Err(code) => {
-                        return Err((code, "Failed create a file"))
+                        return Err((event.rejected(code), "Failed create a file").into())
                     }
                 };
+                // All checks that do not need the content passed
+                start.record("ok");
                 let file = tokio::fs::File::from_std(file);
 
 
-                let counter = Arc::new(AtomicU64::new(0));
+                let counter = staging.accounted.clone();
                 let counter_ = counter.clone();
 
                 let quota_flag = Arc::new(AtomicBool::new(false));
                 let quota_flag_ = quota_flag.clone();
 
+                let throttled : Arc<Mutex<Option<Throttled>>> = Arc::new(Mutex::new(None));
+                let throttled_ = throttled.clone();
+
+                let space_flag = Arc::new(AtomicBool::new(false));
+                let space_flag_ = space_flag.clone();
+
+                let too_large_flag = Arc::new(AtomicBool::new(false));
+                let too_large_flag_ = too_large_flag.clone();
+                let max_file_size = shared_dir.max_file_size;
+                let reservation_ = reservation.clone();
+
                 let quotas_ = quotas.clone();
+                let client_limits_ = client_limits.clone();
+                let client_ip = addr.ip();
 
                 let sink = FramedWrite::new(file, BytesCodec::new());
                 let sink = <FramedWrite<_, _> as SinkExt<axum::body::Bytes>>::sink_map_err(sink, |e : std::io::Error|anyhow::Error::from(e));
 
-                let stream = field.map_err(|e|anyhow::Error::from(e));
+                let throttle = Arc::new(bandwidth.throttle(shared_dir.rate_limit.as_ref(), addr.ip(), Direction::Upload));
+                let stream = with_read_timeout(field.map_err(anyhow::Error::from), client_limits.body_read_timeout());
+                let stream = stream.then(move |x| {
+                    let throttle = throttle.clone();
+                    async move {
+                        if let Ok(b) = &x {
+                            throttle.consume(b.len() as u64).await;
+                        }
+                        x
+                    }
+                });
                 let stream = stream.map(move |x| {
                     if quota_flag_.load(SeqCst) {
                         anyhow::bail!("Quota exceed");
                     }
                     if let Ok(b) = &x {
-                        if quotas_.bytes.bump(b.len() as u64) {
-                            quotas_.bytes.reduce(b.len() as u64);
+                        if max_file_size.is_some_and(|max| counter_.load(SeqCst) + b.len() as u64 > max) {
+                            too_large_flag_.store(true, SeqCst);
+                            anyhow::bail!("File is too large");
+                        }
+                        if let Err(t) = client_limits_.bytes(client_ip, b.len() as u64) {
+                            *throttled_.lock().unwrap() = Some(t);
+                            anyhow::bail!("Per-client byte limit exceeded");
+                        }
+                        if !free_space.consume(b.len() as u64) {
+                            space_flag_.store(true, SeqCst);
+                            anyhow::bail!("Not enough free disk space");
+                        }
+                        let len = b.len() as u64;
+                        let reserved = reservation_.as_ref().map_or(0, |r| r.take(len));
+                        if quotas_.bytes.bump(len - reserved) {
+                            quotas_.bytes.reduce(len - reserved);
+                            // Reserved part is already in the quota; removing the staging file gives it back
+                            counter_.fetch_add(reserved, SeqCst);
                             quota_flag_.store(true, SeqCst);
-                            let remaining = quotas_.bytes.remaining();
-                            if remaining > 0 {
-                                let b = b.slice(..(remaining as usize));
-                                // do not register it in the quota counters - we'll update metadata later
-                                return Ok(b); // fast block to fill the quota to the brim (and prevent duplicate uploads)
-                            } else {
-                                anyhow::bail!("Quota exceed");
-                            }
+                            anyhow::bail!("Quota exceed");
                         }
                         counter_.fetch_add(b.len() as u64, SeqCst);
                     }
                     x
                 });
+                let hasher = Arc::new(Mutex::new(Sha256::new()));
+                let hasher_ = hasher.clone();
+                let stream = stream.inspect(move |x| {
+                    if let Ok(b) = x {
+                        hasher_.lock().unwrap().update(b);
+                    }
+                });
 
                 // Actual data transfer happens here:
                 let ret = stream.forward(sink).await;
 
-                if quota_flag.load(SeqCst) {
-                    let len_accounted = counter.load(SeqCst);
-                    println!("{} {} upload_quota_hit len_so_far={}",  easy_ts(), addr, len_accounted);
-                    let mut renamed = newname.clone().into_os_string();
-                    renamed.push(".partial");
+                let throttled = throttled.lock().unwrap().take();
+                if too_large_flag.load(SeqCst) {
                     drop(ret);
+                    Event { size: Some(counter.load(SeqCst)), ..event }.record("too_large");
+                    drop(staging);
+                    return Err((StatusCode::PAYLOAD_TOO_LARGE, "File is too large").into());
+                }
 
-                    if let Ok(meta) = std::fs::metadata(&newname) {
-                        let len_actual = meta.len();
-                        if len_actual > len_accounted {
-                            quotas.bytes.bump(len_actual - len_accounted);
-                        }
-                        if len_accounted > len_actual {
-                            quotas.bytes.reduce(len_accounted - len_actual);
-                        }
+                let space_exhausted = space_flag.load(SeqCst);
+                if quota_flag.load(SeqCst) || throttled.is_some() || space_exhausted {
+                    let outcome = if throttled.is_some() {
+                        "throttled"
+                    } else if space_exhausted {
+                        "disk_full"
+                    } else {
+                        "quota_exceeded"
+                    };
+                    drop(ret);
+                    Event { size: Some(counter.load(SeqCst)), ..event }.record(outcome);
+                    // Incomplete file is not kept
+                    drop(staging);
+                    if let Some(t) = throttled {
+                        return Err(t.into());
                     }
-
-                    let _ = renamore::rename_exclusive(newname, renamed);
-                    return Err((StatusCode::PAYLOAD_TOO_LARGE, "Disk storage quota exceed"));
+                    if space_exhausted {
+                        return Err((StatusCode::INSUFFICIENT_STORAGE, "Not enough free disk space").into());
+                    }
+                    return Err((StatusCode::PAYLOAD_TOO_LARGE, "Disk storage quota exceed").into());
                 } else {
                     match ret {
                         Ok(()) => {
-                            println!("{} {} upload_finished {:?} len={}", easy_ts(), addr, newname, counter.load(SeqCst));
+                            let len = counter.load(SeqCst);
+                            let newname = staging
+                                .publish(&shared_dir.dir, &filename, "", shared_dir.on_conflict)
+                                .map_err(|code| (event.rejected(code), "Failed create a file"))?;
+                            save_meta(&newname, None, max_downloads, &quotas);
+                            stored.push(Stored {
+                                original_name: original_name.clone(),
+                                name: stored_name(&newname).unwrap_or_default().to_owned(),
+                            });
+                            Event {
+                                stored_name: stored_name(&newname),
+                                size: Some(len),
+                                sha256: Some(&format!("{:x}", hasher.lock().unwrap().clone().finalize())),
+                                ..event
+                            }
+                            .record("ok");
                         }
                         Err(e) => {
+                            Event { size: Some(counter.load(SeqCst)), ..event }.record("failed");
+                            // Staging file with incomplete content is removed on drop
                             warn!("Upload aborted or failed to write file: {e}");
-                            return Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed upload a file"))
+                            return Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed upload a file").into())
                         }
                     }
                 }
@@ -258,5 +821,22 @@ pub(crate) async fn upload(
         }
     }
 
-    Ok(())
+    Ok(Json(stored))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::numbered_name;
+
+    #[test]
+    fn numbered_names() {
+        assert_eq!(numbered_name("photo.jpg", 1), "photo (1).jpg");
+        assert_eq!(numbered_name("archive.tar.gz", 2), "archive (2).tar.gz");
+        assert_eq!(numbered_name("Archive.TAR.XZ", 1), "Archive (1).TAR.XZ");
+        assert_eq!(numbered_name("my.report.pdf", 1), "my.report (1).pdf");
+        assert_eq!(numbered_name("README", 3), "README (3)");
+        assert_eq!(numbered_name(".profile", 1), ".profile (1)");
+        assert_eq!(numbered_name(".tar.gz", 1), ".tar (1).gz");
+        assert_eq!(numbered_name("name.", 1), "name (1).");
+    }
 }
@@ -0,0 +1,174 @@
+use std::{
+    fs::File,
+    io::Write,
+    net::SocketAddr,
+    path::Path,
+    sync::{
+        mpsc::{channel, Sender},
+        OnceLock,
+    },
+    thread::JoinHandle,
+    time::SystemTime,
+};
+
+use anyhow::Context;
+use serde::Serialize;
+use tracing::error;
+
+use axum::http::StatusCode;
+
+use crate::{
+    clientlimits::{Throttled, TooManyUploads},
+    tls::ClientIdentity,
+};
+
+enum Message {
+    Line(String),
+    Stop,
+}
+
+/// Queue of the thread writing audit records, so that recording never waits for the disk
+static AUDIT_LOG: OnceLock<Sender<Message>> = OnceLock::new();
+
+/// Audit log writer thread; dropping it writes out queued records and stops the thread
+pub struct AuditWriter(Option<JoinHandle<()>>);
+
+impl Drop for AuditWriter {
+    fn drop(&mut self) {
+        if let Some(tx) = AUDIT_LOG.get() {
+            let _ = tx.send(Message::Stop);
+        }
+        if let Some(t) = self.0.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+/// Start writing audit records to `--audit-log` file, or stdout if it is not specified or is `-`
+pub fn init(path: Option<&Path>) -> anyhow::Result<AuditWriter> {
+    let mut out: Box<dyn Write + Send> = match path {
+        Some(p) if p != Path::new("-") => Box::new(
+            File::options().create(true).append(true).open(p).with_context(|| format!("opening {p:?}"))?,
+        ),
+        _ => Box::new(std::io::stdout()),
+    };
+    let (tx, rx) = channel();
+    if AUDIT_LOG.set(tx).is_err() {
+        anyhow::bail!("Audit log is already initialized");
+    }
+    let thread = std::thread::spawn(move || {
+        for msg in rx {
+            let Message::Line(line) = msg else { break };
+            if let Err(e) = out.write_all(line.as_bytes()) {
+                error!("Failed to write audit log: {e}");
+            }
+        }
+        let _ = out.flush();
+    });
+    Ok(AuditWriter(Some(thread)))
+}
+
+/// One line of the audit log
+#[derive(Serialize, Default, Clone, Copy)]
+pub struct Event<'a> {
+    /// Seconds since UNIX epoch, filled in by [`Event::record`]
+    pub ts: u64,
+    /// `upload_start`, `upload`, `share_text`, `remove`, `cleanup_delete` or `download_limit_delete`
+    pub action: &'static str,
+    /// `ok` or the reason why the action failed, like `quota_exceeded`
+    pub outcome: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub share: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client: Option<SocketAddr>,
+    /// First name from the client certificate
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identity: Option<&'a str>,
+    /// File name or text share title as sent by the client
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub original_name: Option<&'a str>,
+    /// Name of the file in the shared directory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stored_name: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// Hex SHA-256 of the stored content
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<&'a str>,
+}
+
+impl<'a> Event<'a> {
+    /// Event caused by a client request
+    pub fn request(action: &'static str, share: &'a str, client: SocketAddr, identity: &'a ClientIdentity) -> Event<'a> {
+        Event {
+            action,
+            share: Some(share),
+            client: Some(client),
+            identity: identity.name(),
+            ..Default::default()
+        }
+    }
+
+    /// Record the event with outcome matching the error the request is rejected with, passing the error through
+    pub fn rejected<E: Outcome>(self, e: E) -> E {
+        self.record(e.outcome());
+        e
+    }
+
+    pub fn record(mut self, outcome: &'static str) {
+        self.outcome = outcome;
+        self.ts = SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or(0);
+        if self.client.is_some() {
+            crate::metrics::action(self.action, outcome, self.share, self.size);
+        }
+        let mut line = match serde_json::to_string(&self) {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Failed to serialize audit event: {e}");
+                return;
+            }
+        };
+        line.push('\n');
+        match AUDIT_LOG.get() {
+            Some(tx) => {
+                // Fails only after the writer has stopped at shutdown
+                let _ = tx.send(Message::Line(line));
+            }
+            None => {
+                let _ = std::io::stdout().lock().write_all(line.as_bytes());
+            }
+        }
+    }
+}
+
+/// Errors that requests can be rejected with, for the `outcome` field
+pub trait Outcome {
+    fn outcome(&self) -> &'static str;
+}
+
+impl Outcome for Throttled {
+    fn outcome(&self) -> &'static str {
+        "throttled"
+    }
+}
+
+impl Outcome for TooManyUploads {
+    fn outcome(&self) -> &'static str {
+        "too_many_uploads"
+    }
+}
+
+impl Outcome for StatusCode {
+    fn outcome(&self) -> &'static str {
+        match *self {
+            StatusCode::PAYLOAD_TOO_LARGE => "quota_exceeded",
+            StatusCode::INSUFFICIENT_STORAGE => "disk_full",
+            StatusCode::CONFLICT => "name_conflict",
+            StatusCode::BAD_REQUEST => "bad_request",
+            _ => "failed",
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::ConnectInfo,
+    http::Request,
+    middleware::Next,
+    response::Response,
+    Extension,
+};
+
+use crate::{
+    clientlimits::{mask, PRUNE_THRESHOLD},
+    config::Reloadable,
+    SharedDirectory,
+};
+
+/// Token bucket that allows bursts of up to one second worth of traffic
+pub struct Bucket {
+    rate: u64,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    /// Can become negative when transfer gets ahead of the allowed rate
+    available: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    pub fn new(rate: u64) -> Bucket {
+        Bucket {
+            rate,
+            state: Mutex::new(BucketState {
+                available: rate as f64,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take `len` bytes from the bucket, returning how long should the transfer pause to stay within the rate
+    fn reserve(&self, len: u64) -> Duration {
+        let now = Instant::now();
+        let rate = self.rate.max(1) as f64;
+        let mut s = self.state.lock().unwrap();
+        let refill = now.duration_since(s.last).as_secs_f64() * rate;
+        s.available = (s.available + refill).min(rate);
+        s.last = now;
+        s.available -= len as f64;
+        if s.available < 0.0 {
+            Duration::from_secs_f64(-s.available / rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+/// Separate buckets for each transfer direction
+pub struct RateLimit {
+    upload: Arc<Bucket>,
+    download: Arc<Bucket>,
+}
+
+impl RateLimit {
+    pub fn new(rate: u64) -> RateLimit {
+        RateLimit {
+            upload: Arc::new(Bucket::new(rate)),
+            download: Arc::new(Bucket::new(rate)),
+        }
+    }
+
+    fn bucket(&self, direction: Direction) -> Arc<Bucket> {
+        match direction {
+            Direction::Upload => self.upload.clone(),
+            Direction::Download => self.download.clone(),
+        }
+    }
+}
+
+/// All buckets a particular transfer is subject to
+pub struct Throttle {
+    buckets: Vec<Arc<Bucket>>,
+}
+
+impl Throttle {
+    pub fn is_unlimited(&self) -> bool {
+        self.buckets.is_empty()
+    }
+
+    /// Wait until `len` more bytes can be transferred
+    pub async fn consume(&self, len: u64) {
+        let wait = self
+            .buckets
+            .iter()
+            .map(|b| b.reserve(len))
+            .max()
+            .unwrap_or(Duration::ZERO);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+pub struct BandwidthConfig {
+    pub global_rate: Option<u64>,
+    pub client_rate: Option<u64>,
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+}
+
+/// Caps from configuration and the global buckets for them
+struct Caps {
+    config: BandwidthConfig,
+    global: Option<RateLimit>,
+}
+
+/// Global and per-client-address bandwidth caps. Per-share caps are stored in [`SharedDirectory`].
+pub struct Bandwidth {
+    /// Replaced on configuration reload; transfers in progress keep buckets they started with
+    caps: Reloadable<Caps>,
+    clients: Mutex<HashMap<IpAddr, Arc<RateLimit>>>,
+}
+
+impl Bandwidth {
+    pub fn new(config: BandwidthConfig) -> Bandwidth {
+        Bandwidth {
+            caps: Reloadable::new(Caps {
+                global: config.global_rate.map(RateLimit::new),
+                config,
+            }),
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn set_config(&self, config: BandwidthConfig) {
+        self.caps.set(Caps {
+            global: config.global_rate.map(RateLimit::new),
+            config,
+        });
+        // Subnet prefixes could have changed as well
+        self.clients.lock().unwrap().clear();
+    }
+
+    pub fn throttle(&self, share: Option<&RateLimit>, client: IpAddr, direction: Direction) -> Throttle {
+        let caps = self.caps.get();
+        let mut buckets = Vec::with_capacity(3);
+        if let Some(g) = &caps.global {
+            buckets.push(g.bucket(direction));
+        }
+        if let Some(s) = share {
+            buckets.push(s.bucket(direction));
+        }
+        if let Some(rate) = caps.config.client_rate {
+            let key = mask(client, caps.config.ipv4_prefix, caps.config.ipv6_prefix);
+            let mut clients = self.clients.lock().unwrap();
+            if clients.len() >= PRUNE_THRESHOLD {
+                // Entries not referenced by any ongoing transfer can be forgotten
+                clients.retain(|_, l| Arc::strong_count(&l.upload) > 1 || Arc::strong_count(&l.download) > 1);
+            }
+            let limit = clients
+                .entry(key)
+                .or_insert_with(|| Arc::new(RateLimit::new(rate)));
+            if limit.upload.rate != rate {
+                // Left from before a configuration reload
+                *limit = Arc::new(RateLimit::new(rate));
+            }
+            buckets.push(limit.bucket(direction));
+        }
+        Throttle { buckets }
+    }
+}
+
+/// Middleware for the `ServeDir` fallback that slows down response bodies
+pub(crate) async fn throttle_downloads<B>(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(shared_dir): Extension<Arc<SharedDirectory>>,
+    Extension(bandwidth): Extension<Arc<Bandwidth>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let throttle = bandwidth.throttle(shared_dir.rate_limit.as_ref(), addr.ip(), Direction::Download);
+    let response = next.run(req).await;
+    if throttle.is_unlimited() {
+        return response;
+    }
+    let throttle = Arc::new(throttle);
+    let wait = move |len| {
+        let throttle = throttle.clone();
+        async move { throttle.consume(len).await }
+    };
+    response.map(|body| crate::body::observe(body, None, wait, || ()))
+}
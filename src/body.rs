@@ -0,0 +1,38 @@
+use std::future::Future;
+
+use axum::body::{BoxBody, HttpBody, StreamBody};
+
+/// Wrap a response body, calling `on_chunk` with the length of each chunk before passing it on (awaiting it delays
+/// the chunk) and `on_end` once the whole body has been sent. `on_end` is not called if the body fails or is dropped
+/// earlier. Hyper stops polling the body once `Content-Length` bytes are sent, so if the length is known
+/// (`expected`), the end is detected by byte count.
+pub fn observe<C, F, E>(body: BoxBody, expected: Option<u64>, on_chunk: C, on_end: E) -> BoxBody
+where
+    C: FnMut(u64) -> F + Send + 'static,
+    F: Future<Output = ()> + Send,
+    E: FnOnce() + Send + 'static,
+{
+    let state = (body, on_chunk, Some(on_end), 0u64);
+    let stream = futures::stream::unfold(state, move |(mut body, mut on_chunk, mut on_end, mut sent)| async move {
+        let Some(chunk) = body.data().await else {
+            if let Some(f) = on_end.take() {
+                f();
+            }
+            return None;
+        };
+        match &chunk {
+            Ok(c) => {
+                on_chunk(c.len() as u64).await;
+                sent += c.len() as u64;
+                if expected.is_some_and(|len| sent >= len) {
+                    if let Some(f) = on_end.take() {
+                        f();
+                    }
+                }
+            }
+            Err(_) => on_end = None,
+        }
+        Some((chunk, (body, on_chunk, on_end, sent)))
+    });
+    axum::body::boxed(StreamBody::new(stream))
+}
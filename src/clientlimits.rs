@@ -0,0 +1,305 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    http::{header::RETRY_AFTER, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use tracing::warn;
+
+use crate::config::Reloadable;
+
+/// Do not bother pruning expired or idle per-client entries until there are at least this many clients tracked
+pub const PRUNE_THRESHOLD: usize = 1024;
+
+/// Returned when a client (or its subnet) exceeds one of the per-client limits
+pub struct Throttled {
+    pub retry_after: Duration,
+}
+
+impl IntoResponse for Throttled {
+    fn into_response(self) -> Response {
+        // Round up, so that client does not retry a moment too early
+        let secs = self.retry_after.as_secs() + u64::from(self.retry_after.subsec_nanos() > 0);
+        (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(RETRY_AFTER, HeaderValue::from(secs))],
+            "Too many requests from your address, try again later",
+        )
+            .into_response()
+    }
+}
+
+/// Returned when too many uploads are already in progress (from all clients or from this client)
+pub enum TooManyUploads {
+    Global,
+    Client,
+}
+
+impl IntoResponse for TooManyUploads {
+    fn into_response(self) -> Response {
+        let (status, msg) = match self {
+            TooManyUploads::Global => (StatusCode::SERVICE_UNAVAILABLE, "Too many uploads in progress, try again later"),
+            TooManyUploads::Client => (StatusCode::TOO_MANY_REQUESTS, "Too many uploads in progress from your address, try again later"),
+        };
+        (status, [(RETRY_AFTER, HeaderValue::from_static("1"))], msg).into_response()
+    }
+}
+
+pub struct ClientLimitsConfig {
+    pub max_requests: Option<u64>,
+    pub max_files: Option<u64>,
+    pub max_bytes: Option<u64>,
+    pub window: Duration,
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+    pub max_concurrent_uploads: Option<usize>,
+    pub client_max_concurrent_uploads: Option<usize>,
+    pub body_read_timeout: Option<Duration>,
+}
+
+struct ClientUsage {
+    window_start: Instant,
+    requests: u64,
+    files: u64,
+    bytes: u64,
+}
+
+impl ClientUsage {
+    fn new(window_start: Instant) -> ClientUsage {
+        ClientUsage {
+            window_start,
+            requests: 0,
+            files: 0,
+            bytes: 0,
+        }
+    }
+}
+
+/// Per-client-address (or per-subnet) accounting of mutating requests, uploaded files and bytes within a time window
+pub struct ClientLimits {
+    /// Replaced on configuration reload; usage accounted so far is kept
+    config: Reloadable<ClientLimitsConfig>,
+    clients: Mutex<HashMap<IpAddr, ClientUsage>>,
+    uploads: Mutex<ActiveUploads>,
+}
+
+#[derive(Default)]
+struct ActiveUploads {
+    total: usize,
+    per_client: HashMap<IpAddr, usize>,
+}
+
+/// Occupied upload slot, released on drop
+pub struct UploadSlot {
+    limits: Arc<ClientLimits>,
+    key: IpAddr,
+}
+
+impl Drop for UploadSlot {
+    fn drop(&mut self) {
+        let mut u = self.limits.uploads.lock().unwrap();
+        u.total -= 1;
+        if let Some(n) = u.per_client.get_mut(&self.key) {
+            *n -= 1;
+            if *n == 0 {
+                u.per_client.remove(&self.key);
+            }
+        }
+    }
+}
+
+pub fn mask(addr: IpAddr, ipv4_prefix: u8, ipv6_prefix: u8) -> IpAddr {
+    match addr.to_canonical() {
+        IpAddr::V4(a) => {
+            let m = u32::MAX.checked_shl(32 - u32::from(ipv4_prefix.min(32))).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(a) & m))
+        }
+        IpAddr::V6(a) => {
+            let m = u128::MAX.checked_shl(128 - u32::from(ipv6_prefix.min(128))).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(a) & m))
+        }
+    }
+}
+
+impl ClientLimits {
+    pub fn new(config: ClientLimitsConfig) -> ClientLimits {
+        ClientLimits {
+            config: Reloadable::new(config),
+            clients: Mutex::new(HashMap::new()),
+            uploads: Mutex::new(ActiveUploads::default()),
+        }
+    }
+
+    pub fn set_config(&self, config: ClientLimitsConfig) {
+        self.config.set(config);
+    }
+
+    /// Address or subnet the client is accounted as
+    pub fn client_key(&self, addr: IpAddr) -> IpAddr {
+        let config = self.config.get();
+        mask(addr, config.ipv4_prefix, config.ipv6_prefix)
+    }
+
+    fn with_usage<R>(&self, addr: IpAddr, f: impl FnOnce(&mut ClientUsage, Duration) -> R) -> R {
+        let now = Instant::now();
+        let window = self.config.get().window;
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= PRUNE_THRESHOLD {
+            clients.retain(|_, u| now.duration_since(u.window_start) < window);
+        }
+        let usage = clients
+            .entry(self.client_key(addr))
+            .or_insert_with(|| ClientUsage::new(now));
+        if now.duration_since(usage.window_start) >= window {
+            *usage = ClientUsage::new(now);
+        }
+        let retry_after = window.saturating_sub(now.duration_since(usage.window_start));
+        f(usage, retry_after)
+    }
+
+    /// Maximum time to wait for each next chunk of an upload
+    pub fn body_read_timeout(&self) -> Option<Duration> {
+        self.config.get().body_read_timeout
+    }
+
+    /// Occupy one of the concurrent upload slots for the duration of an upload
+    pub fn upload_slot(self: &Arc<Self>, addr: IpAddr) -> Result<UploadSlot, TooManyUploads> {
+        let key = self.client_key(addr);
+        let config = self.config.get();
+        let mut u = self.uploads.lock().unwrap();
+        if config.max_concurrent_uploads.is_some_and(|l| u.total >= l) {
+            warn!("Rejecting upload from {addr}: too many concurrent uploads");
+            return Err(TooManyUploads::Global);
+        }
+        let n = u.per_client.get(&key).copied().unwrap_or(0);
+        if config.client_max_concurrent_uploads.is_some_and(|l| n >= l) {
+            warn!("Rejecting upload from {addr}: too many concurrent uploads from {key}");
+            return Err(TooManyUploads::Client);
+        }
+        u.per_client.insert(key, n + 1);
+        u.total += 1;
+        Ok(UploadSlot {
+            limits: self.clone(),
+            key,
+        })
+    }
+
+    /// Account one mutating request (upload, text share or removal)
+    pub fn request(&self, addr: IpAddr) -> Result<(), Throttled> {
+        let limit = self.config.get().max_requests;
+        self.with_usage(addr, |u, retry_after| {
+            if limit.is_some_and(|l| u.requests >= l) {
+                return Err(Throttled { retry_after });
+            }
+            u.requests += 1;
+            Ok(())
+        })
+    }
+
+    /// Account one newly created file
+    pub fn file(&self, addr: IpAddr) -> Result<(), Throttled> {
+        let limit = self.config.get().max_files;
+        self.with_usage(addr, |u, retry_after| {
+            if limit.is_some_and(|l| u.files >= l) {
+                return Err(Throttled { retry_after });
+            }
+            u.files += 1;
+            Ok(())
+        })
+    }
+
+    /// Check that the client may create one more file of `len` bytes, without accounting it yet
+    pub fn check_file(&self, addr: IpAddr, len: u64) -> Result<(), Throttled> {
+        let config = self.config.get();
+        let (max_files, max_bytes) = (config.max_files, config.max_bytes);
+        self.with_usage(addr, |u, retry_after| {
+            if max_files.is_some_and(|l| u.files >= l) || max_bytes.is_some_and(|l| u.bytes + len > l) {
+                return Err(Throttled { retry_after });
+            }
+            Ok(())
+        })
+    }
+
+    /// Account `len` more bytes written on behalf of the client. Bytes are accounted even if the limit gets exceeded.
+    pub fn bytes(&self, addr: IpAddr, len: u64) -> Result<(), Throttled> {
+        let limit = self.config.get().max_bytes;
+        self.with_usage(addr, |u, retry_after| {
+            u.bytes += len;
+            if limit.is_some_and(|l| u.bytes > l) {
+                return Err(Throttled { retry_after });
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::IpAddr, time::Duration};
+
+    use super::{mask, ClientLimits, ClientLimitsConfig};
+
+    fn ip(x: &str) -> IpAddr {
+        x.parse().unwrap()
+    }
+
+    #[test]
+    fn masks() {
+        assert_eq!(mask(ip("192.0.2.77"), 32, 128), ip("192.0.2.77"));
+        assert_eq!(mask(ip("192.0.2.77"), 24, 128), ip("192.0.2.0"));
+        assert_eq!(mask(ip("192.0.2.77"), 0, 128), ip("0.0.0.0"));
+        assert_eq!(mask(ip("192.0.2.77"), 40, 128), ip("192.0.2.77"));
+        assert_eq!(mask(ip("2001:db8:1:2:3:4:5:6"), 32, 128), ip("2001:db8:1:2:3:4:5:6"));
+        assert_eq!(mask(ip("2001:db8:1:2:3:4:5:6"), 32, 64), ip("2001:db8:1:2::"));
+        assert_eq!(mask(ip("2001:db8:1:2:3:4:5:6"), 32, 0), ip("::"));
+        // IPv4-mapped IPv6 addresses are treated as IPv4 ones
+        assert_eq!(mask(ip("::ffff:192.0.2.77"), 24, 128), ip("192.0.2.0"));
+        assert_eq!(mask(ip("::ffff:192.0.2.77"), 32, 0), ip("192.0.2.77"));
+    }
+
+    fn limits(window: Duration) -> ClientLimits {
+        ClientLimits::new(ClientLimitsConfig {
+            max_requests: Some(2),
+            max_files: None,
+            max_bytes: Some(100),
+            window,
+            ipv4_prefix: 24,
+            ipv6_prefix: 64,
+            max_concurrent_uploads: None,
+            client_max_concurrent_uploads: None,
+            body_read_timeout: None,
+        })
+    }
+
+    #[test]
+    fn subnet_shares_limits() {
+        let l = limits(Duration::from_secs(3600));
+        assert!(l.request(ip("192.0.2.1")).is_ok());
+        assert!(l.request(ip("192.0.2.2")).is_ok());
+        let throttled = l.request(ip("192.0.2.3")).err().unwrap();
+        assert!(throttled.retry_after <= Duration::from_secs(3600));
+        assert!(l.request(ip("198.51.100.1")).is_ok());
+    }
+
+    #[test]
+    fn window_expires() {
+        let window = Duration::from_millis(200);
+        let l = limits(window);
+        let a = ip("2001:db8::1");
+        assert!(l.request(a).is_ok());
+        assert!(l.request(a).is_ok());
+        assert!(l.request(a).is_err());
+        assert!(l.bytes(a, 101).is_err());
+        assert!(l.check_file(a, 1).is_err());
+        std::thread::sleep(window);
+        assert!(l.request(a).is_ok());
+        assert!(l.check_file(a, 100).is_ok());
+        assert!(l.bytes(a, 100).is_ok());
+        assert!(l.check_file(a, 1).is_err());
+    }
+}
@@ -0,0 +1,97 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use anyhow::Context;
+use axum::{extract::State, http::Request, middleware::Next, response::Response};
+
+/// Find `--config FILE` among raw command line arguments, before they can be parsed
+pub fn config_path(args: &[String]) -> Option<PathBuf> {
+    let i = args.iter().position(|a| a == "--config")?;
+    args.get(i + 1).map(PathBuf::from)
+}
+
+/// Read arguments from configuration file: one option per line, like `--max-files 100` or `--count-allocated-blocks`.
+/// Value is the rest of the line, so it can contain spaces. Empty lines and lines starting with `#` are ignored.
+/// Returns arguments of each line separately.
+pub fn read_args(path: &Path) -> anyhow::Result<Vec<Vec<String>>> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("reading {path:?}"))?;
+    Ok(parse_lines(&text))
+}
+
+fn parse_lines(text: &str) -> Vec<Vec<String>> {
+    let mut lines = vec![];
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once(char::is_whitespace) {
+            Some((option, value)) if option.starts_with('-') => lines.push(vec![option.to_owned(), value.trim().to_owned()]),
+            _ => lines.push(vec![line.to_owned()]),
+        }
+    }
+    lines
+}
+
+/// Append arguments from configuration file lines to command line ones, skipping lines with options
+/// that are already given on the command line, so that the command line takes precedence.
+/// `short` maps short options to their long forms. Listen addresses (positional arguments) from both are used.
+pub fn merge_args(cli: &[String], file: Vec<Vec<String>>, short: &[(&str, &str)]) -> Vec<String> {
+    let long = |x: &str| short.iter().find(|(s, _)| *s == x).map_or(x, |(_, l)| l).to_owned();
+    let given: Vec<String> = cli.iter().skip(1).filter(|a| a.starts_with('-')).map(|a| long(a)).collect();
+    let mut args = cli.to_vec();
+    for line in file {
+        if line[0].starts_with('-') && given.contains(&long(&line[0])) {
+            continue;
+        }
+        args.extend(line);
+    }
+    args
+}
+
+/// Settings that can be replaced while requests that already started keep using the old ones
+pub struct Reloadable<T>(RwLock<Arc<T>>);
+
+impl<T> Reloadable<T> {
+    pub fn new(x: T) -> Reloadable<T> {
+        Reloadable(RwLock::new(Arc::new(x)))
+    }
+
+    pub fn get(&self) -> Arc<T> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn set(&self, x: T) {
+        *self.0.write().unwrap() = Arc::new(x);
+    }
+}
+
+/// Middleware that provides current version of reloadable settings to handlers as `Extension<Arc<T>>`
+pub(crate) async fn current<T: Send + Sync + 'static, B>(
+    State(settings): State<Arc<Reloadable<T>>>,
+    mut req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    req.extensions_mut().insert(settings.get());
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(x: &[&str]) -> Vec<String> {
+        x.iter().map(|&a| a.to_owned()).collect()
+    }
+
+    #[test]
+    fn command_line_takes_precedence() {
+        let file = parse_lines("# comment\n\n--max-files 10\n--transient-title My files\n-t /srv/t\n--count-allocated-blocks\n0.0.0.0:80\n");
+        let cli = strings(&["duplo", "--transiet-directory", "/tmp/t", "--max-files", "5", "--config", "duplo.conf", "[::]:80"]);
+        let merged = merge_args(&cli, file, &[("-t", "--transiet-directory")]);
+        let mut expected = cli.clone();
+        expected.extend(strings(&["--transient-title", "My files", "--count-allocated-blocks", "0.0.0.0:80"]));
+        assert_eq!(merged, expected);
+    }
+}
@@ -1,9 +1,15 @@
-use std::{sync::{atomic::{AtomicU64, Ordering::SeqCst}, Arc}, path::Path, time::{Duration, SystemTime}};
+use std::{collections::HashSet, fs::Metadata, os::unix::fs::MetadataExt, sync::{atomic::{AtomicBool, AtomicU64, Ordering::SeqCst}, mpsc::{Receiver, RecvTimeoutError}, Arc, Mutex}, path::{Path, PathBuf}, time::{Duration, SystemTime}};
 
-use tracing::{error, debug, info};
+use tracing::{error, debug, info, warn};
+
+use crate::{config::Reloadable, symlinks::{is_allowed, is_special}, SharedDirectory};
+
+/// Re-query free disk space after this many bytes are written by an upload
+const FREE_SPACE_RECHECK_INTERVAL: u64 = 1024 * 1024;
 
 pub struct QuotaCounter {
-    pub allowed: u64,
+    /// Limit, can be changed by configuration reload
+    pub allowed: AtomicU64,
     pub current: AtomicU64,
 }
 
@@ -11,76 +17,391 @@ impl QuotaCounter {
     pub fn get(&self) -> u64 {
         self.current.load(SeqCst)
     }
+    pub fn allowed(&self) -> u64 {
+        self.allowed.load(SeqCst)
+    }
     /// Returns true if after adding the quota becomes exceeded (you need to manually `reduce` it then)
     pub fn bump(&self, val: u64) -> bool {
-        self.current.fetch_add(val, SeqCst) + val > self.allowed
+        self.current.fetch_add(val, SeqCst) + val > self.allowed()
     }
     pub fn reduce(&self, val: u64) {
-        if self.current.fetch_sub(val, SeqCst) < val {
+        let prev = self.current.fetch_sub(val, SeqCst);
+        if prev < val {
             self.current.store(0, SeqCst);
+            warn!("Quota counter went below zero ({prev} - {val}), counting from zero now");
         }
     }
+    /// Set the counter to `val`, unless it no longer holds `expected`. Returns whether it was set.
+    pub fn replace(&self, expected: u64, val: u64) -> bool {
+        self.current.compare_exchange(expected, val, SeqCst, SeqCst).is_ok()
+    }
     pub fn is_exceed(&self) -> bool {
-        self.current.load(SeqCst) >= self.allowed
+        self.current.load(SeqCst) >= self.allowed()
     }
     pub fn is_close_to_exeeed(&self) -> bool {
-        self.current.load(SeqCst) as f32 >= self.allowed as f32 * 0.9
+        self.current.load(SeqCst) as f32 >= self.allowed() as f32 * 0.9
     }
     pub fn remaining(&self) -> u64 {
-        self.allowed.saturating_sub(self.current.load(SeqCst))
+        self.allowed().saturating_sub(self.current.load(SeqCst))
+    }
+}
+
+/// Bytes available to unprivileged users on the filesystem containing `dir`
+pub fn free_space(dir: &Path) -> std::io::Result<u64> {
+    let st = nix::sys::statvfs::statvfs(dir)?;
+    #[allow(clippy::useless_conversion)]
+    Ok(u64::from(st.blocks_available()) * u64::from(st.fragment_size()))
+}
+
+/// Free space on the filesystem containing `dir` in excess of `reserved` bytes. Failure to query is logged and treated as unlimited space.
+fn space_beyond_reserve(dir: &Path, reserved: u64) -> u64 {
+    match free_space(dir) {
+        Ok(x) => x.saturating_sub(reserved),
+        Err(e) => {
+            warn!("Failed to query free space of {dir:?}: {e}");
+            u64::MAX
+        }
+    }
+}
+
+/// Tracks whether an ongoing write is about to eat into the reserved free disk space
+pub struct FreeSpaceCheck {
+    dir: PathBuf,
+    reserved: u64,
+    allowance: u64,
+    unchecked: u64,
+}
+
+impl FreeSpaceCheck {
+    fn refresh(&mut self) {
+        self.allowance = space_beyond_reserve(&self.dir, self.reserved);
+        self.unchecked = 0;
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.allowance == 0
+    }
+
+    /// Returns false if writing `len` more bytes would leave less than reserved free space
+    pub fn consume(&mut self, len: u64) -> bool {
+        if len > self.allowance || self.unchecked >= FREE_SPACE_RECHECK_INTERVAL {
+            self.refresh();
+        }
+        if len > self.allowance {
+            return false;
+        }
+        self.allowance -= len;
+        self.unchecked += len;
+        true
     }
 }
 
+/// Bytes booked in advance in the byte quota for an upload of known size. Unused part is released on drop.
+pub struct ByteReservation {
+    quotas: Arc<Quotas>,
+    remaining: AtomicU64,
+}
+
+impl ByteReservation {
+    /// Use up to `len` bytes of the reservation, returning how many bytes were covered by it
+    pub fn take(&self, len: u64) -> u64 {
+        let prev = self
+            .remaining
+            .fetch_update(SeqCst, SeqCst, |r| Some(r.saturating_sub(len)))
+            .unwrap();
+        let taken = prev.min(len);
+        self.quotas.pending_reservations.fetch_sub(taken, SeqCst);
+        taken
+    }
+}
+
+impl Drop for ByteReservation {
+    fn drop(&mut self) {
+        let remaining = self.remaining.load(SeqCst);
+        self.quotas.pending_reservations.fetch_sub(remaining, SeqCst);
+        self.quotas.bytes.reduce(remaining);
+    }
+}
+
+/// Files and bytes found in a shared directory by the last scan
+#[derive(Clone, Copy)]
+pub struct ShareUsage {
+    pub share: &'static str,
+    pub files: u64,
+    pub bytes: u64,
+}
+
 pub struct Quotas {
     pub bytes: QuotaCounter,
     pub files: QuotaCounter,
+    /// Amount of free disk space that uploads should not consume
+    pub reserved_space: AtomicU64,
+    /// Account allocated disk blocks instead of apparent file sizes
+    pub count_blocks: AtomicBool,
+    /// Bytes counted in `bytes` that are booked by [`ByteReservation`]s, but not yet written to disk
+    pending_reservations: AtomicU64,
+    /// Per-share results of the last startup or reconciliation scan; counters above are for all shares together
+    scanned: Mutex<Vec<ShareUsage>>,
 }
 
 impl Quotas {
-    pub fn new(files_limit: u64, bytes_limit: u64) -> Quotas {
+    pub fn new(files_limit: u64, bytes_limit: u64, reserved_space: u64, count_blocks: bool) -> Quotas {
         Quotas {
+            reserved_space: AtomicU64::new(reserved_space),
+            count_blocks: AtomicBool::new(count_blocks),
+            pending_reservations: AtomicU64::new(0),
+            scanned: Mutex::new(vec![]),
             bytes: QuotaCounter {
-                allowed: bytes_limit,
+                allowed: AtomicU64::new(bytes_limit),
                 current: AtomicU64::new(0),
             },
             files: QuotaCounter {
-                allowed: files_limit,
+                allowed: AtomicU64::new(files_limit),
                 current: AtomicU64::new(0),
             },
         }
     }
 
-    pub fn scan_and_add(&self, dir: &Path) -> anyhow::Result<()> {
-        let files = std::fs::read_dir(dir)?;
+    /// Apply new limits from reloaded configuration. Current usage is kept.
+    pub fn set_limits(&self, files_limit: u64, bytes_limit: u64, reserved_space: u64) {
+        self.files.allowed.store(files_limit, SeqCst);
+        self.bytes.allowed.store(bytes_limit, SeqCst);
+        self.reserved_space.store(reserved_space, SeqCst);
+    }
+
+    /// Book `len` bytes in the byte quota, unless it would become exceeded
+    pub fn reserve_bytes(self: &Arc<Self>, len: u64) -> Option<ByteReservation> {
+        if self.bytes.bump(len) {
+            self.bytes.reduce(len);
+            return None;
+        }
+        self.pending_reservations.fetch_add(len, SeqCst);
+        Some(ByteReservation {
+            quotas: self.clone(),
+            remaining: AtomicU64::new(len),
+        })
+    }
+
+    pub fn free_space_check(&self, dir: &Path) -> FreeSpaceCheck {
+        let mut c = FreeSpaceCheck {
+            dir: dir.to_owned(),
+            reserved: self.reserved_space.load(SeqCst),
+            allowance: 0,
+            unchecked: 0,
+        };
+        c.refresh();
+        c
+    }
+
+    /// How many bytes can be uploaded to `dir`, considering both the quota and actual free space on the filesystem
+    pub fn remaining_capacity(&self, dir: &Path) -> u64 {
+        self.bytes.remaining().min(space_beyond_reserve(dir, self.reserved_space.load(SeqCst)))
+    }
+
+    /// How many bytes a file or directory with given metadata occupies for the purpose of byte quota
+    pub fn file_size(&self, meta: &Metadata) -> u64 {
+        if self.count_blocks.load(SeqCst) {
+            meta.blocks() * 512
+        } else if meta.is_dir() {
+            0
+        } else {
+            meta.len()
+        }
+    }
+
+    /// Remove file (or symlink) `path` from a shared directory together with its metadata and history, updating quota counters.
+    /// Returns what the file was.
+    pub fn remove_file(&self, path: &Path) -> std::io::Result<Metadata> {
+        let m = std::fs::symlink_metadata(path)?;
+        std::fs::remove_file(path)?;
+        self.forget_file(path, &m);
+        Ok(m)
+    }
+
+    /// Update quota counters after file `path` (as described by `m`) is removed or replaced, and remove its metadata and history
+    pub fn forget_file(&self, path: &Path, m: &Metadata) {
+        // Other hardlinks keep the data in place; symlinks and special files are not counted
+        if m.is_file() && m.nlink() <= 1 {
+            if path.file_name().and_then(|x| x.to_str()).is_none_or(crate::meta::counts_as_file) {
+                self.files.reduce(1);
+            }
+            self.bytes.reduce(self.file_size(m));
+        }
+        crate::meta::remove(path, self);
+    }
+
+    pub fn scan_and_add(&self, shares: &[(&'static str, PathBuf)]) -> anyhow::Result<()> {
+        let mut seen_inodes = HashSet::new();
+        let mut scanned = vec![];
+        for (share, dir) in shares {
+            let (files, bytes) = self.scan(dir, &mut seen_inodes)?;
+            self.files.bump(files);
+            self.bytes.bump(bytes);
+            scanned.push(ShareUsage { share, files, bytes });
+        }
+        *self.scanned.lock().unwrap() = scanned;
+        Ok(())
+    }
+
+    /// Per-share usage as of the last scan
+    pub fn scanned(&self) -> Vec<ShareUsage> {
+        self.scanned.lock().unwrap().clone()
+    }
+
+    /// Count files and bytes in `dir` and its subdirectories the same way quota counters account them.
+    /// Files with multiple hardlinks are counted once per `seen_inodes`. Symlinks and special files hold no shared data and are not counted.
+    pub fn scan(&self, dir: &Path, seen_inodes: &mut HashSet<(u64, u64)>) -> anyhow::Result<(u64, u64)> {
         let mut ctr1 = 0usize;
         let mut ctr2 = 0usize;
-        for f in files {
-            ctr1+=1;
-            let Ok(f) = f else { continue }; 
-            let Ok(meta) = f.metadata() else { continue }; 
-            self.files.bump(1);
-            self.bytes.bump(meta.len());
-            // Note: not handling u64 overflows
-            ctr2+=1;
-
+        let mut total_files = 0u64;
+        let mut total_bytes = 0u64;
+        let mut to_visit = vec![dir.to_owned()];
+        while let Some(d) = to_visit.pop() {
+            let files = match std::fs::read_dir(&d) {
+                Ok(x) => x,
+                Err(e) if d == dir => return Err(e.into()),
+                Err(e) => {
+                    warn!("Failed to scan {d:?} for quota: {e}");
+                    continue;
+                }
+            };
+            for f in files {
+                ctr1+=1;
+                let Ok(f) = f else { continue }; 
+                let Ok(meta) = f.metadata() else { continue }; 
+                ctr2+=1;
+                if meta.is_symlink() || crate::symlinks::is_special(meta.file_type()) {
+                    continue;
+                }
+                if meta.nlink() > 1 && !meta.is_dir() && !seen_inodes.insert((meta.dev(), meta.ino())) {
+                    continue;
+                }
+                if meta.is_dir() {
+                    to_visit.push(f.path());
+                } else if f.file_name().to_str().is_none_or(crate::meta::counts_as_file) {
+                    total_files += 1;
+                }
+                // Note: not handling u64 overflows
+                total_bytes += self.file_size(&meta);
+            }
         }
         if ctr1 != ctr2 {
             error!("Scanning some files for quota failed");
         }
-        Ok(())
+        Ok((total_files, total_bytes))
     }
 }
 
-pub fn cleanup_task(transient_dir: &Path, cleanup_time: time::Time, max_age: Duration, quotas: Arc<Quotas>) -> anyhow::Result<()> { 
+/// Sleep for `duration` or until something is sent to `stop` or its sender is dropped. Returns `true` if asked to stop.
+fn wait_or_stop(stop: &Receiver<()>, duration: Duration) -> bool {
+    !matches!(stop.recv_timeout(duration), Err(RecvTimeoutError::Timeout))
+}
+
+/// Periodically rescan shared directories and correct quota counters if files were added or removed behind Duplo's back
+pub fn reconcile_task(shares: &[(&'static str, PathBuf)], interval: Duration, quotas: Arc<Quotas>, stop: Receiver<()>) {
+    'rescan: loop {
+        if wait_or_stop(&stop, interval) {
+            return;
+        }
+
+        let before = (quotas.files.get(), quotas.bytes.get());
+        let mut files = 0u64;
+        let mut bytes = 0u64;
+        let mut seen_inodes = HashSet::new();
+        let mut scanned = vec![];
+        for (share, dir) in shares {
+            let (f, b) = match quotas.scan(dir, &mut seen_inodes) {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("Failed to rescan {dir:?} for quota: {e}");
+                    continue 'rescan;
+                }
+            };
+            debug!("Directory {dir:?} has {f} files and {b} bytes");
+            files += f;
+            bytes += b;
+            scanned.push(ShareUsage { share, files: f, bytes: b });
+        }
+        let after = (quotas.files.get(), quotas.bytes.get());
+        if before != after {
+            debug!("Quota counters changed during rescan, not reconciling them this time");
+            continue;
+        }
+        // Reserved, but not yet uploaded bytes are not on disk yet
+        let bytes = bytes + quotas.pending_reservations.load(SeqCst);
+
+        let previous = std::mem::replace(&mut *quotas.scanned.lock().unwrap(), scanned.clone());
+        if (files, bytes) == after {
+            continue;
+        }
+        // Uploads or removals finishing right now must not get their accounting overwritten
+        let files_set = quotas.files.replace(after.0, files);
+        let bytes_set = quotas.bytes.replace(after.1, bytes);
+        if !(files_set && bytes_set) {
+            debug!("Quota counters changed while reconciling, leaving them for the next rescan");
+            if !(files_set || bytes_set) {
+                continue;
+            }
+        }
+        warn!("Quota counters drifted from directory contents: files {} -> {files}, bytes {} -> {bytes}", after.0, after.1);
+        for (now, before) in scanned.iter().zip(&previous) {
+            if (now.files, now.bytes) != (before.files, before.bytes) {
+                warn!(
+                    "Share {} has {} files and {} bytes, was {} files and {} bytes at the previous scan",
+                    now.share, now.files, now.bytes, before.files, before.bytes
+                );
+            }
+        }
+        crate::metrics::quota_drift_corrected();
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct CleanupSchedule {
+    /// Time of day (UTC) to run cleanup at
+    pub time: time::Time,
+    /// Remove files older than this
+    pub max_age: Duration,
+}
+
+enum Wakeup {
+    Timeout,
+    /// Schedule was updated, waiting should be restarted
+    Updated,
+    Stop,
+}
+
+/// Sleep for `duration`, applying schedule update if it arrives meanwhile
+fn wait_for_cleanup(updates: &Receiver<CleanupSchedule>, schedule: &mut CleanupSchedule, duration: Duration) -> Wakeup {
+    match updates.recv_timeout(duration) {
+        Err(RecvTimeoutError::Timeout) => Wakeup::Timeout,
+        Err(RecvTimeoutError::Disconnected) => Wakeup::Stop,
+        Ok(new) => {
+            *schedule = new;
+            debug!("Cleanup schedule updated");
+            Wakeup::Updated
+        }
+    }
+}
+
+/// Periodically remove old files from transient directory. Symlinks are removed (not their targets) only if its symlink policy allows them.
+/// New schedules can be sent to `updates`; dropping its sender stops the task.
+pub fn cleanup_task(transient: &Reloadable<SharedDirectory>, mut schedule: CleanupSchedule, quotas: Arc<Quotas>, updates: Receiver<CleanupSchedule>) -> anyhow::Result<()> {
     loop {
         let begin = time::OffsetDateTime::now_utc();
-        let mut next_cleanup = begin.replace_time(cleanup_time);
+        let mut next_cleanup = begin.replace_time(schedule.time);
         if next_cleanup < begin {
             next_cleanup += Duration::from_secs(24*3600);
         }
         let to_wait : Duration = (next_cleanup - begin).try_into()?;
         debug!("Cleanup task is waiting for {:?}", to_wait);
-        std::thread::sleep(to_wait);
+        match wait_for_cleanup(&updates, &mut schedule, to_wait) {
+            Wakeup::Timeout => (),
+            Wakeup::Updated => continue,
+            Wakeup::Stop => return Ok(()),
+        }
+        let max_age = schedule.max_age;
+        let started = std::time::Instant::now();
 
         let mut bytes_retained = 0u64;
         let mut files_retained = 0u64;
@@ -89,14 +410,26 @@ pub fn cleanup_task(transient_dir: &Path, cleanup_time: time::Time, max_age: Dur
         let mut errors = 0u64;
 
         let now = SystemTime::now();
+        let share = transient.get();
+        let transient_dir = &share.dir;
 
         let files = std::fs::read_dir(transient_dir)?;
         for f in files {
             errors+=1;
             let Ok(f) = f else { continue };
             let Ok(meta) = f.metadata() else { continue };
-            if meta.is_dir() || meta.is_symlink() { errors-=1; continue; }
+            if meta.is_dir() || is_special(meta.file_type()) { errors-=1; continue; }
+            if meta.is_symlink() && !is_allowed(transient_dir, Path::new(&f.file_name()), share.symlinks) { errors-=1; continue; }
+            // Symlinks are not counted in quota
+            let size = if meta.is_file() { quotas.file_size(&meta) } else { 0 };
             let Ok(modified) = meta.modified() else { continue };
+            // Metadata goes away together with its file, unless the file is already gone
+            if let Some(owner) = f.file_name().to_str().and_then(crate::meta::owner_name) {
+                if transient_dir.join(owner).exists() {
+                    errors-=1;
+                    continue;
+                }
+            }
 
             let retain = match now.duration_since(modified) {
                 Ok(x) => {
@@ -108,18 +441,26 @@ pub fn cleanup_task(transient_dir: &Path, cleanup_time: time::Time, max_age: Dur
 
             if retain {
                 files_retained+=1;
-                bytes_retained+=meta.len();
+                bytes_retained+=size;
                 errors-=1;
             } else {
-                match std::fs::remove_file(f.path()) {
-                    Ok(()) => {
+                let name = f.file_name();
+                let event = crate::audit::Event {
+                    action: "cleanup_delete",
+                    share: Some("transient"),
+                    stored_name: name.to_str(),
+                    size: Some(meta.len()),
+                    ..Default::default()
+                };
+                match quotas.remove_file(&f.path()) {
+                    Ok(_) => {
+                        event.record("ok");
                         files_removed+=1;
-                        bytes_removed+=meta.len();
-                        quotas.files.reduce(1);
-                        quotas.bytes.reduce(meta.len());
+                        bytes_removed+=size;
                         errors-=1;
                     }
                     Err(e) => {
+                        event.record("failed");
                         info!("Error removing file {:?}: {e}", f.path())
                     }
                 }
@@ -127,7 +468,16 @@ pub fn cleanup_task(transient_dir: &Path, cleanup_time: time::Time, max_age: Dur
         }
 
         println!("cleanup, removed {files_removed} files ({bytes_removed} bytes), retained {files_retained} files ({bytes_retained} bytes); {errors} errors");
+        crate::metrics::cleanup_finished(started.elapsed(), files_removed, bytes_removed);
 
-        std::thread::sleep(Duration::from_secs(60));
+        // Avoid running cleanup twice at the same scheduled time
+        let pause_until = std::time::Instant::now() + Duration::from_secs(60);
+        loop {
+            match wait_for_cleanup(&updates, &mut schedule, pause_until.saturating_duration_since(std::time::Instant::now())) {
+                Wakeup::Timeout => break,
+                Wakeup::Updated => continue,
+                Wakeup::Stop => return Ok(()),
+            }
+        }
     }
 }
@@ -0,0 +1,227 @@
+use std::{
+    collections::HashMap,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    http::{
+        header::{CONTENT_LENGTH, IF_RANGE, RANGE},
+        Method, Request, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use tracing::{info, warn};
+
+use crate::{audit::Event, disksize::Quotas, meta, SharedDirectory};
+
+/// Enforces download limits of files that should be deleted after being downloaded (or viewed) N times.
+/// Downloads that are in progress are counted too, so that the limit cannot be exceeded by parallel requests.
+pub struct DownloadLimits {
+    in_progress: Mutex<HashMap<PathBuf, u32>>,
+    quotas: Arc<Quotas>,
+}
+
+/// Download that has been allowed to start; counted when finished, released when dropped
+pub struct DownloadGuard {
+    limits: Arc<DownloadLimits>,
+    path: PathBuf,
+    share: &'static str,
+}
+
+impl DownloadLimits {
+    pub fn new(quotas: Arc<Quotas>) -> DownloadLimits {
+        DownloadLimits {
+            in_progress: Mutex::new(HashMap::new()),
+            quotas,
+        }
+    }
+
+    /// Check download limit of `path`. `None` means it does not have one.
+    pub fn begin(self: &Arc<Self>, path: &Path, share: &'static str) -> Result<Option<DownloadGuard>, StatusCode> {
+        let mut in_progress = self.in_progress.lock().unwrap();
+        let m = meta::load(path);
+        let Some(max) = m.max_downloads else { return Ok(None) };
+        let n = in_progress.entry(path.to_owned()).or_default();
+        if m.downloads.saturating_add(*n) >= max {
+            return Err(StatusCode::NOT_FOUND);
+        }
+        *n += 1;
+        Ok(Some(DownloadGuard {
+            limits: self.clone(),
+            path: path.to_owned(),
+            share,
+        }))
+    }
+}
+
+impl DownloadGuard {
+    /// Count completed download, removing the file if it was the last one allowed
+    pub fn finish(self) {
+        let quotas = &self.limits.quotas;
+        let in_progress = self.limits.in_progress.lock().unwrap();
+        let mut m = meta::load(&self.path);
+        m.downloads += 1;
+        // Saved even if the file is going to be removed, so that it is not served anymore if removal fails
+        if let Err(e) = meta::save(&self.path, &m, quotas) {
+            warn!("Cannot update download count of {:?}: {e}", self.path);
+        }
+        // This download is still in progress until the guard is dropped, so the file can be removed without the lock
+        drop(in_progress);
+        if m.max_downloads.is_some_and(|max| m.downloads < max) {
+            return;
+        }
+        let event = Event {
+            action: "download_limit_delete",
+            share: Some(self.share),
+            stored_name: self.path.file_name().and_then(|x| x.to_str()),
+            ..Default::default()
+        };
+        match quotas.remove_file(&self.path) {
+            Ok(metadata) => {
+                Event {
+                    size: Some(metadata.len()),
+                    ..event
+                }
+                .record("ok");
+                info!("Removed {:?} after {} downloads", self.path, m.downloads);
+            }
+            Err(e) => {
+                event.record("failed");
+                warn!("Cannot remove {:?} after reaching download limit: {e}", self.path);
+            }
+        }
+    }
+}
+
+impl Drop for DownloadGuard {
+    fn drop(&mut self) {
+        let mut in_progress = self.limits.in_progress.lock().unwrap();
+        if let Some(n) = in_progress.get_mut(&self.path) {
+            *n -= 1;
+            if *n == 0 {
+                in_progress.remove(&self.path);
+            }
+        }
+    }
+}
+
+/// Middleware for the `ServeDir` fallback that refuses downloads over the limit and counts completed ones
+pub(crate) async fn limit_downloads<B>(
+    Extension(shared_dir): Extension<Arc<SharedDirectory>>,
+    Extension(limits): Extension<Arc<DownloadLimits>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if req.method() != Method::GET {
+        return next.run(req).await;
+    }
+    let Some(name) = served_file_name(req.uri().path()) else { return next.run(req).await };
+    let guard = match limits.begin(&shared_dir.dir.join(name), shared_dir.name) {
+        Ok(Some(g)) => g,
+        Ok(None) => return next.run(req).await,
+        Err(code) => return code.into_response(),
+    };
+    // Partial content is not counted, and `bytes=0-` would get the whole file that way
+    let mut req = req;
+    req.headers_mut().remove(RANGE);
+    req.headers_mut().remove(IF_RANGE);
+    let response = next.run(req).await;
+    // Partial content does not count as a download
+    if response.status() != StatusCode::OK {
+        return response;
+    }
+    let expected = response
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok());
+    // Counted only when the whole body has been sent
+    response.map(|body| crate::body::observe(body, expected, |_| async {}, move || guard.finish()))
+}
+
+/// Name of the top-level file `ServeDir` serves for URI path `path`, if it is one.
+/// Decoded and split the same way as `ServeDir` does, so that `./x`, `x/` or `%2E/x` all resolve to `x`.
+fn served_file_name(path: &str) -> Option<String> {
+    let path = percent_encoding::percent_decode_str(path.trim_start_matches('/')).decode_utf8().ok()?;
+    let mut name = None;
+    for c in Path::new(&*path).components() {
+        match c {
+            Component::Normal(x) if name.is_none() => name = Some(x.to_str()?.to_owned()),
+            Component::CurDir => (),
+            _ => return None,
+        }
+    }
+    name.filter(|x| !x.starts_with('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::{
+        body::Body,
+        http::{header::RANGE, Request, StatusCode},
+        middleware,
+        routing::get_service,
+        Extension, Router,
+    };
+    use tower::ServiceExt;
+    use tower_http::services::ServeDir;
+
+    use super::{limit_downloads, served_file_name, DownloadLimits};
+    use crate::{actions::OnConflict, disksize::Quotas, meta, ownership::Ownership, symlinks::SymlinkPolicy, tls::WriteAccess, SharedDirectory};
+
+    #[test]
+    fn served_file_names() {
+        for path in ["/secret.txt", "/./secret.txt", "/secret.txt/", "//secret.txt", "/%2E/secret.txt", "/secret%2Etxt"] {
+            assert_eq!(served_file_name(path).as_deref(), Some("secret.txt"), "{path}");
+        }
+        for path in ["/", "/.", "/.secret.txt", "/%2Esecret.txt", "/dir/secret.txt", "/../secret.txt", "/%2E%2E/secret.txt", "/%FF"] {
+            assert_eq!(served_file_name(path), None, "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn range_request_counts_whole_download() {
+        let dir = std::env::temp_dir().join(format!("duplo-test-downloads-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("secret.txt");
+        std::fs::write(&file, "secret").unwrap();
+        let quotas = Arc::new(Quotas::new(100, 1000, 0, false));
+        let limited = meta::FileMeta {
+            max_downloads: Some(1),
+            ..Default::default()
+        };
+        meta::save(&file, &limited, &quotas).unwrap();
+        let shared_dir = SharedDirectory {
+            name: "transient",
+            dir: dir.clone(),
+            title: String::new(),
+            rate_limit: None,
+            max_file_size: None,
+            write_access: WriteAccess::Anyone,
+            url_prefix: String::new(),
+            content_security_policy: axum::http::HeaderValue::from_static("sandbox"),
+            render_markdown: false,
+            on_conflict: OnConflict::Rename,
+            symlinks: SymlinkPolicy::Deny,
+            ownership: Ownership { mode: None, group: None },
+        };
+        let app = Router::new()
+            .fallback_service(get_service(ServeDir::new(&dir)).layer(middleware::from_fn(limit_downloads)))
+            .layer(Extension(Arc::new(shared_dir)))
+            .layer(Extension(Arc::new(DownloadLimits::new(quotas))));
+        let get = || Request::get("/secret.txt").header(RANGE, "bytes=0-").body(Body::empty()).unwrap();
+
+        let response = app.clone().oneshot(get()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(hyper::body::to_bytes(response.into_body()).await.unwrap(), "secret");
+        assert!(!file.exists());
+        let response = app.oneshot(get()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
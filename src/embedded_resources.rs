@@ -13,8 +13,6 @@ use axum::http::Response;
 
 use axum::extract::Path;
 
-use axum;
-
 use axum::http::header;
 use axum::http::header::CACHE_CONTROL;
 use include_dir::include_dir;
@@ -1,9 +1,11 @@
-use std::{sync::Arc, time::UNIX_EPOCH};
+use std::{path::Path, sync::Arc, time::UNIX_EPOCH};
 
 use askama::Template;
 use askama_axum::IntoResponse;
 use axum::extract::OriginalUri;
 use axum::http::header::CACHE_CONTROL;
+use axum::http::Request;
+use axum::middleware::Next;
 use axum::response::{Redirect, Response};
 use axum::{
     self,
@@ -14,7 +16,17 @@ use axum::{
 use humansize::BINARY;
 
 use crate::disksize::Quotas;
-use crate::SharedDirectory;
+use crate::{highlight, meta, symlinks, SharedDirectory};
+
+/// Content-Security-Policy of pages generated by Duplo itself
+const PAGE_CONTENT_SECURITY_POLICY: &str = "default-src 'none'; img-src 'self'; style-src 'self' 'unsafe-inline'; script-src 'self' 'unsafe-inline'; connect-src 'self'; font-src 'self'; frame-ancestors 'none'";
+
+/// Headers of pages generated by Duplo itself: revalidated on each visit and with restrictive Content-Security-Policy
+pub(crate) fn page_headers(response: &mut Response) {
+    let h = response.headers_mut();
+    h.insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    h.insert(axum::http::header::CONTENT_SECURITY_POLICY, HeaderValue::from_static(PAGE_CONTENT_SECURITY_POLICY));
+}
 
 pub struct FileInfo {
     pub time: u64,
@@ -27,6 +39,13 @@ pub struct FileInfo {
     pub hour: u8,
     pub minute: u8,
     pub second: u8,
+
+    /// Has a syntax highlighted view page
+    pub viewable: bool,
+    /// The file gets deleted after this many more downloads
+    pub downloads_left: Option<u32>,
+    /// Text share that has been edited
+    pub has_history: bool,
 }
 
 #[derive(Template)]
@@ -35,6 +54,13 @@ pub struct ViewTemplate {
     pub title: String,
     pub files: Vec<FileInfo>,
     pub err: String,
+    pub capacity: String,
+    /// Value for Dropzone's `maxFilesize` option
+    pub max_filesize_mib: f64,
+    /// Prepended to absolute links
+    pub url_prefix: String,
+    /// Choices for text share language
+    pub languages: Vec<&'static str>,
 }
 
 #[axum::debug_handler]
@@ -61,6 +87,19 @@ pub(crate) async fn serve_view(
             err += "Disk storage quota is close to being full\n"
         }
     }
+    let capacity = quotas.remaining_capacity(&shared_dir.dir);
+    if capacity == 0 && !quotas.bytes.is_exceed() {
+        err += "Not enough free disk space\n"
+    }
+    let mut capacity = format!("{} available for uploads", humansize::format_size(capacity, BINARY));
+    if let Some(max) = shared_dir.max_file_size {
+        capacity += &format!(", up to {} per file", humansize::format_size(max, BINARY));
+    }
+    let max_filesize_mib = match shared_dir.max_file_size {
+        Some(max) => max as f64 / (1024.0 * 1024.0),
+        None => 32.0 * 1024.0,
+    };
+    let mut with_meta = std::collections::HashSet::new();
     let mut files: Vec<FileInfo> = files
         .flat_map(|f| match f {
             Err(e) => {
@@ -71,11 +110,18 @@ pub(crate) async fn serve_view(
             Ok(f) => {
                 if let Ok(mut name) = f.file_name().into_string() {
                     if name.starts_with('.') {
+                        if let Some(owner) = meta::owner_name(&name) {
+                            with_meta.insert(owner.to_owned());
+                        }
+                        return None;
+                    }
+                    if !symlinks::is_allowed(&shared_dir.dir, Path::new(&name), shared_dir.symlinks) {
                         return None;
                     }
                     let mut time = 0;
                     let mut size = String::new();
-                    if let Ok(metadata) = f.metadata() {
+                    // Allowed symlinks are shown as their targets
+                    if let Ok(metadata) = std::fs::metadata(f.path()) {
                         size = humansize::format_size(metadata.len(), BINARY);
                         if let Ok(modified) = metadata.modified() {
                             if let Ok(dur) = modified.duration_since(UNIX_EPOCH) {
@@ -89,6 +135,9 @@ pub(crate) async fn serve_view(
                     let tf = time::OffsetDateTime::from_unix_timestamp(time as i64)
                         .unwrap_or(time::OffsetDateTime::UNIX_EPOCH);
                     Some(FileInfo {
+                        viewable: name.ends_with(".txt"),
+                        downloads_left: None,
+                        has_history: false,
                         name,
                         size,
                         time,
@@ -106,15 +155,37 @@ pub(crate) async fn serve_view(
             }
         })
         .collect();
+    for f in files.iter_mut().filter(|f| with_meta.contains(&f.name)) {
+        let m = meta::load(&shared_dir.dir.join(&f.name));
+        f.viewable |= m.language.is_some();
+        f.downloads_left = m.downloads_left();
+        // Diff of a file with download limit is not shown
+        f.has_history = m.current_version() > 1 && m.max_downloads.is_none();
+    }
     files.sort_by_key(|fi| fi.time);
     let mut response = ViewTemplate {
         title: shared_dir.title.clone(),
         files,
         err,
+        capacity,
+        max_filesize_mib,
+        url_prefix: shared_dir.url_prefix.clone(),
+        languages: highlight::language_names(),
     }
     .into_response();
-    let h = response.headers_mut();
-    h.insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
-    h.insert(axum::http::header::CONTENT_SECURITY_POLICY, HeaderValue::from_static("default-src 'none'; img-src 'self'; style-src 'self' 'unsafe-inline'; script-src 'self' 'unsafe-inline'; connect-src 'self'; font-src 'self'; frame-ancestors 'none'"));
+    page_headers(&mut response);
     Ok(response)
 }
+
+/// Middleware that adds `--content-security-policy` header to served files
+pub(crate) async fn content_security_policy<B>(
+    Extension(shared_dir): Extension<Arc<SharedDirectory>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let mut response = next.run(req).await;
+    response
+        .headers_mut()
+        .append(axum::http::header::CONTENT_SECURITY_POLICY, shared_dir.content_security_policy.clone());
+    response
+}
@@ -0,0 +1,134 @@
+use unicode_normalization::UnicodeNormalization;
+
+/// Limit for sanitized names, in bytes. Leaves room within the usual 255-byte limit for
+/// collision numbers and hidden staging, metadata and history names derived from it.
+const MAX_NAME_BYTES: usize = 200;
+
+/// Extensions longer than this are not preserved when truncating
+const MAX_EXTENSION_BYTES: usize = 16;
+
+/// Used when nothing is left of the name
+const DEFAULT_NAME: &str = "unnamed";
+
+/// Names that cannot be used as base names on Windows, regardless of extension
+const WINDOWS_RESERVED: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9", "LPT1", "LPT2",
+    "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Invisible formatting characters (Unicode category Cf), which can hide or reorder parts of a name,
+/// like right-to-left override making `exe.txt` look like `txt.exe`
+fn is_format_char(c: char) -> bool {
+    matches!(
+        c,
+        '\u{00AD}'
+            | '\u{0600}'..='\u{0605}'
+            | '\u{061C}'
+            | '\u{06DD}'
+            | '\u{070F}'
+            | '\u{180E}'
+            | '\u{200B}'..='\u{200F}'
+            | '\u{202A}'..='\u{202E}'
+            | '\u{2060}'..='\u{2064}'
+            | '\u{2066}'..='\u{206F}'
+            | '\u{FEFF}'
+            | '\u{FFF9}'..='\u{FFFB}'
+            | '\u{110BD}'
+            | '\u{1BCA0}'..='\u{1BCA3}'
+            | '\u{1D173}'..='\u{1D17A}'
+            | '\u{E0001}'
+            | '\u{E0020}'..='\u{E007F}'
+    )
+}
+
+fn is_unsafe_char(c: char) -> bool {
+    c.is_control() || is_format_char(c) || matches!(c, '/' | '\\' | '<' | '>' | ':' | '"' | '|' | '?' | '*')
+}
+
+/// Cut `x` to at most `max` bytes on a character boundary
+fn truncate(x: &str, max: usize) -> &str {
+    if x.len() <= max {
+        return x;
+    }
+    let mut end = max;
+    while !x.is_char_boundary(end) {
+        end -= 1;
+    }
+    &x[..end]
+}
+
+/// Turn a client-supplied name into one that is safe to store and visible in the listing:
+/// only the last path component is used, the name is normalized to NFC, control, invisible formatting and
+/// Windows-unsafe characters are replaced by `_`, leading dots (hidden files), trailing dots
+/// and spaces and Windows reserved names are defused, and the length is limited.
+pub fn sanitize(name: &str) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name.nfc().map(|c| if is_unsafe_char(c) { '_' } else { c }).collect();
+    let name = name.trim().trim_end_matches(['.', ' ']);
+    let mut name = match name.strip_prefix('.') {
+        Some(rest) => format!("_{rest}"),
+        None => name.to_owned(),
+    };
+    let base = name.split('.').next().unwrap_or_default().trim_end();
+    if WINDOWS_RESERVED.iter().any(|r| r.eq_ignore_ascii_case(base)) {
+        name.insert(0, '_');
+    }
+    if name.len() > MAX_NAME_BYTES {
+        name = match name.rsplit_once('.') {
+            Some((stem, ext)) if !stem.is_empty() && ext.len() <= MAX_EXTENSION_BYTES => {
+                format!("{}.{ext}", truncate(stem, MAX_NAME_BYTES - ext.len() - 1))
+            }
+            _ => truncate(&name, MAX_NAME_BYTES).to_owned(),
+        };
+    }
+    if name.is_empty() {
+        return DEFAULT_NAME.to_owned();
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sanitize;
+
+    #[test]
+    fn sanitized_names() {
+        let cases = [
+            ("photo.jpg", "photo.jpg"),
+            ("../../etc/passwd", "passwd"),
+            ("C:\\Users\\me\\report.pdf", "report.pdf"),
+            ("a<b>c:d\"e|f?g*h.txt", "a_b_c_d_e_f_g_h.txt"),
+            ("tab\there\n.txt", "tab_here_.txt"),
+            ("evil\u{202E}txt.exe", "evil_txt.exe"),
+            ("zero\u{200B}width\u{FEFF}.txt", "zero_width_.txt"),
+            ("tag\u{E0041}.txt", "tag_.txt"),
+            (".hidden", "_hidden"),
+            ("name. . ", "name"),
+            ("  spaced  ", "spaced"),
+            ("con.txt", "_con.txt"),
+            ("LPT1", "_LPT1"),
+            ("console.txt", "console.txt"),
+            ("e\u{301}.txt", "\u{e9}.txt"),
+            ("", "unnamed"),
+            ("...", "unnamed"),
+            ("/", "unnamed"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(sanitize(input), expected, "{input:?}");
+        }
+    }
+
+    #[test]
+    fn long_names_are_truncated() {
+        let long = format!("{}.txt", "a".repeat(300));
+        let s = sanitize(&long);
+        assert_eq!(s.len(), 200);
+        assert!(s.ends_with("a.txt"));
+
+        let s = sanitize(&"\u{e9}".repeat(150));
+        assert!(s.len() <= 200 && s.chars().all(|c| c == '\u{e9}'));
+
+        let s = sanitize(&format!("a.{}", "b".repeat(300)));
+        assert_eq!(s.len(), 200);
+    }
+}
@@ -0,0 +1,67 @@
+use std::sync::{Arc, Weak};
+
+use axum::{http::StatusCode, response::IntoResponse, Extension, Json};
+use nix::unistd::{access, AccessFlags};
+use serde_json::{json, Value};
+
+use crate::{config::Reloadable, disksize::Quotas, SharedDirectory};
+
+/// What `/readyz` checks
+pub struct Readiness {
+    pub shares: [Arc<Reloadable<SharedDirectory>>; 2],
+    pub quotas: Arc<Quotas>,
+    /// Only the cleanup thread holds the strong reference, so it is gone when the thread panics
+    pub cleanup_alive: Weak<()>,
+}
+
+/// Handler for `/healthz`: the process is up and serves requests
+pub async fn healthz() -> Json<Value> {
+    Json(json!({ "status": "ok" }))
+}
+
+/// Handler for `/readyz`: not ready unless share directories are writable and cleanup task is running.
+/// When there is no room left for uploads, the service is still ready (files can be downloaded), but reported as degraded.
+pub async fn readyz(Extension(r): Extension<Arc<Readiness>>) -> impl IntoResponse {
+    let mut ready = true;
+    let mut full = false;
+    let mut shares = serde_json::Map::new();
+    for share in &r.shares {
+        let share = share.get();
+        let writable = match access(&share.dir, AccessFlags::W_OK | AccessFlags::X_OK) {
+            Ok(()) if share.dir.is_dir() => Ok(()),
+            Ok(()) => Err("not a directory".to_owned()),
+            Err(e) => Err(e.desc().to_owned()),
+        };
+        let capacity = r.quotas.remaining_capacity(&share.dir);
+        ready &= writable.is_ok();
+        full |= capacity == 0;
+        shares.insert(
+            share.name.to_owned(),
+            json!({
+                "writable": writable.is_ok(),
+                "error": writable.err(),
+                "capacity_bytes": capacity,
+            }),
+        );
+    }
+    let cleanup_alive = r.cleanup_alive.strong_count() > 0;
+    ready &= cleanup_alive;
+    full |= r.quotas.files.remaining() == 0 || r.quotas.bytes.remaining() == 0;
+    let (status, text) = match (ready, full) {
+        (false, _) => (StatusCode::SERVICE_UNAVAILABLE, "unavailable"),
+        (true, true) => (StatusCode::OK, "degraded"),
+        (true, false) => (StatusCode::OK, "ok"),
+    };
+    let body = json!({
+        "status": text,
+        "shares": shares,
+        "cleanup_task_alive": cleanup_alive,
+        "quota": {
+            "files_used": r.quotas.files.get(),
+            "files_allowed": r.quotas.files.allowed(),
+            "bytes_used": r.quotas.bytes.get(),
+            "bytes_allowed": r.quotas.bytes.allowed(),
+        },
+    });
+    (status, Json(body))
+}
@@ -0,0 +1,151 @@
+use std::{path::Path, sync::{Arc, OnceLock}};
+
+use askama::Template;
+use askama_axum::IntoResponse;
+use axum::{
+    extract,
+    http::StatusCode,
+    response::{Redirect, Response},
+    Extension,
+};
+use syntect::{
+    easy::HighlightLines,
+    highlighting::{Theme, ThemeSet},
+    html::{styled_line_to_highlighted_html, IncludeBackground},
+    parsing::{SyntaxReference, SyntaxSet},
+    util::LinesWithEndings,
+};
+
+use crate::{actions::allowed_filename, downloads::DownloadLimits, file_list, meta, symlinks, SharedDirectory};
+
+/// Larger files are redirected to their raw version instead of being highlighted
+const MAX_HIGHLIGHTED_SIZE: u64 = 2 * 1024 * 1024;
+
+const THEME: &str = "InspiredGitHub";
+
+fn syntaxes() -> &'static SyntaxSet {
+    static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+fn theme() -> &'static Theme {
+    static THEMES: OnceLock<ThemeSet> = OnceLock::new();
+    &THEMES.get_or_init(ThemeSet::load_defaults).themes[THEME]
+}
+
+/// Names of languages that can be chosen for a text share
+pub fn language_names() -> Vec<&'static str> {
+    let mut names: Vec<&str> = syntaxes().syntaxes().iter().filter(|s| !s.hidden).map(|s| s.name.as_str()).collect();
+    names.sort_unstable_by_key(|n| n.to_lowercase());
+    names
+}
+
+pub fn is_known_language(name: &str) -> bool {
+    syntaxes().find_syntax_by_name(name).is_some()
+}
+
+/// Guess language by file extension of the title (ignoring added `.txt`) or by the first line (e.g. shebang)
+fn detect_syntax(title: &str, text: &str) -> Option<&'static SyntaxReference> {
+    let ss = syntaxes();
+    let title = title.strip_suffix(".txt").unwrap_or(title);
+    Path::new(title)
+        .extension()
+        .and_then(|e| e.to_str())
+        .and_then(|e| ss.find_syntax_by_extension(e))
+        .or_else(|| ss.find_syntax_by_first_line(text.lines().next()?))
+        .filter(|s| s.name != ss.find_syntax_plain_text().name)
+}
+
+pub fn detect_language(title: &str, text: &str) -> Option<String> {
+    detect_syntax(title, text).map(|s| s.name.clone())
+}
+
+/// One line of the text, already converted to HTML
+pub struct Line {
+    pub number: usize,
+    pub html: String,
+}
+
+#[derive(Template)]
+#[template(path = "text_view.html")]
+pub struct TextViewTemplate {
+    pub name: String,
+    pub language: String,
+    pub background: String,
+    pub lines: Vec<Line>,
+    /// Prepended to absolute links
+    pub url_prefix: String,
+}
+
+fn highlight(text: &str, syntax: &SyntaxReference) -> Result<Vec<Line>, syntect::Error> {
+    let mut h = HighlightLines::new(syntax, theme());
+    let mut lines = vec![];
+    for (i, line) in LinesWithEndings::from(text).enumerate() {
+        let ranges = h.highlight_line(line, syntaxes())?;
+        let html = styled_line_to_highlighted_html(&ranges, IncludeBackground::No)?;
+        lines.push(Line { number: i + 1, html });
+    }
+    Ok(lines)
+}
+
+/// Page with syntax highlighted text file, with line numbers that can be linked to as `#L<number>`
+#[axum::debug_handler]
+pub(crate) async fn serve_text_view(
+    extract::Path(name): extract::Path<String>,
+    Extension(shared_dir): Extension<Arc<SharedDirectory>>,
+    Extension(download_limits): Extension<Arc<DownloadLimits>>,
+) -> Result<Response, StatusCode> {
+    if !allowed_filename(&name) || name.starts_with('.') {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if !symlinks::is_allowed(&shared_dir.dir, std::path::Path::new(&name), shared_dir.symlinks) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let path = shared_dir.dir.join(&name);
+    let metadata = tokio::fs::metadata(&path).await.map_err(|_| StatusCode::NOT_FOUND)?;
+    if !metadata.is_file() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    if metadata.len() > MAX_HIGHLIGHTED_SIZE {
+        return Ok(Redirect::to(&format!("../{name}")).into_response());
+    }
+    // Viewing counts as downloading
+    let download = download_limits.begin(&path, shared_dir.name)?;
+    let data = tokio::fs::read(&path).await.map_err(|e| {
+        tracing::error!("Reading {path:?}: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let lines = tokio::task::spawn_blocking(move || {
+        let text = String::from_utf8_lossy(&data);
+        let ss = syntaxes();
+        let syntax = meta::load(&path)
+            .language
+            .and_then(|l| ss.find_syntax_by_name(&l))
+            .or_else(|| detect_syntax(&name, &text))
+            .unwrap_or_else(|| ss.find_syntax_plain_text());
+        highlight(&text, syntax).map(|lines| (name, syntax.name.clone(), lines))
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let (name, language, lines) = lines.map_err(|e| {
+        tracing::error!("Highlighting: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    if let Some(d) = download {
+        d.finish();
+    }
+    let background = match theme().settings.background {
+        Some(c) => format!("#{:02x}{:02x}{:02x}", c.r, c.g, c.b),
+        None => "#ffffff".to_owned(),
+    };
+    let mut response = TextViewTemplate {
+        name,
+        language,
+        background,
+        lines,
+        url_prefix: shared_dir.url_prefix.clone(),
+    }
+    .into_response();
+    file_list::page_headers(&mut response);
+    Ok(response)
+}
@@ -0,0 +1,127 @@
+use std::{path::Path, sync::Arc};
+
+use askama::Template;
+use askama_axum::IntoResponse;
+use axum::{
+    extract::{self, Query},
+    http::StatusCode,
+    response::Response,
+    Extension,
+};
+use serde::Deserialize;
+use similar::{ChangeTag, TextDiff};
+
+use crate::{actions::allowed_filename, file_list, meta, symlinks, SharedDirectory};
+
+/// Versions larger than this are not compared
+const MAX_DIFF_SIZE: u64 = 2 * 1024 * 1024;
+
+#[derive(Deserialize)]
+pub struct DiffQuery {
+    /// Older version; defaults to the one before `to`
+    from: Option<u32>,
+    /// Newer version; defaults to the current one
+    to: Option<u32>,
+}
+
+pub struct DiffLine {
+    /// `+`, `-` or space
+    pub sign: char,
+    pub class: &'static str,
+    pub old_number: Option<usize>,
+    pub new_number: Option<usize>,
+    pub text: String,
+}
+
+#[derive(Template)]
+#[template(path = "diff.html")]
+pub struct DiffTemplate {
+    pub name: String,
+    pub from: u32,
+    pub to: u32,
+    pub current: u32,
+    pub lines: Vec<DiffLine>,
+    /// Prepended to absolute links
+    pub url_prefix: String,
+}
+
+/// Content of version `n` of text share at `path`
+fn read_version(path: &Path, n: u32, current: u32) -> Result<String, StatusCode> {
+    let file = if n == current {
+        path.to_owned()
+    } else {
+        meta::version_path(path, n).ok_or(StatusCode::NOT_FOUND)?
+    };
+    let metadata = std::fs::metadata(&file).map_err(|_| StatusCode::NOT_FOUND)?;
+    if metadata.len() > MAX_DIFF_SIZE {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    let data = std::fs::read(&file).map_err(|e| {
+        tracing::error!("Reading {file:?}: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(String::from_utf8_lossy(&data).into_owned())
+}
+
+/// Page with line differences between two versions of an edited text share
+#[axum::debug_handler]
+pub(crate) async fn serve_diff(
+    extract::Path(name): extract::Path<String>,
+    Query(q): Query<DiffQuery>,
+    Extension(shared_dir): Extension<Arc<SharedDirectory>>,
+) -> Result<Response, StatusCode> {
+    if !allowed_filename(&name) || name.starts_with('.') {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if !symlinks::is_allowed(&shared_dir.dir, std::path::Path::new(&name), shared_dir.symlinks) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let path = shared_dir.dir.join(&name);
+    let file_meta = meta::load(&path);
+    // A diff shows the content without counting it as a download
+    if file_meta.max_downloads.is_some() {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let current = file_meta.current_version();
+    let to = q.to.unwrap_or(current);
+    let from = q.from.unwrap_or(to.saturating_sub(1)).max(1);
+    if to > current || from >= to {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let lines = tokio::task::spawn_blocking(move || {
+        let old = read_version(&path, from, current)?;
+        let new = read_version(&path, to, current)?;
+        let diff = TextDiff::from_lines(&old, &new);
+        let lines = diff
+            .iter_all_changes()
+            .map(|c| {
+                let (sign, class) = match c.tag() {
+                    ChangeTag::Delete => ('-', "diff-delete"),
+                    ChangeTag::Insert => ('+', "diff-insert"),
+                    ChangeTag::Equal => (' ', "diff-equal"),
+                };
+                DiffLine {
+                    sign,
+                    class,
+                    old_number: c.old_index().map(|i| i + 1),
+                    new_number: c.new_index().map(|i| i + 1),
+                    text: c.value().trim_end_matches(['\n', '\r']).to_owned(),
+                }
+            })
+            .collect::<Vec<_>>();
+        Ok::<_, StatusCode>(lines)
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)??;
+    let mut response = DiffTemplate {
+        name,
+        from,
+        to,
+        current,
+        lines,
+        url_prefix: shared_dir.url_prefix.clone(),
+    }
+    .into_response();
+    file_list::page_headers(&mut response);
+    Ok(response)
+}
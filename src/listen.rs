@@ -0,0 +1,195 @@
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use anyhow::Context as _;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+};
+use tracing::{error, info, warn};
+
+/// Where to accept connections from, as specified on the command line
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+    /// All sockets passed by systemd socket activation
+    Systemd,
+}
+
+pub fn parse_listen_addr(x: &str) -> Result<ListenAddr, String> {
+    if x == "systemd" {
+        return Ok(ListenAddr::Systemd);
+    }
+    if let Some(path) = x.strip_prefix("unix:") {
+        return Ok(ListenAddr::Unix(path.into()));
+    }
+    x.parse()
+        .map(ListenAddr::Tcp)
+        .map_err(|_| format!("Invalid listen address `{x}`, expected `IP:PORT`, `unix:PATH` or `systemd`"))
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Address that Unix socket clients are reported with, as they do not have a meaningful one.
+/// Unless a trusted proxy tells the real address, all such clients share one entry of per-client limits.
+pub const UNIX_PEER_ADDR: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+impl Listener {
+    pub async fn accept(&self) -> io::Result<(Connection, SocketAddr)> {
+        match self {
+            Listener::Tcp(l) => {
+                let (s, addr) = l.accept().await?;
+                Ok((Connection::Tcp(s), addr))
+            }
+            Listener::Unix(l) => {
+                let (s, _) = l.accept().await?;
+                Ok((Connection::Unix(s), UNIX_PEER_ADDR))
+            }
+        }
+    }
+
+    /// TCP port number the listener is bound to, if it is a TCP listener
+    pub fn port(&self) -> Option<u16> {
+        match self {
+            Listener::Tcp(l) => l.local_addr().ok().map(|a| a.port()),
+            Listener::Unix(_) => None,
+        }
+    }
+}
+
+/// Bind or take over (from systemd) listening sockets for all specified addresses
+pub async fn bind_all(addrs: &[ListenAddr]) -> anyhow::Result<Vec<Listener>> {
+    let mut listeners = Vec::with_capacity(addrs.len());
+    for a in addrs {
+        match a {
+            ListenAddr::Tcp(s) => {
+                listeners.push(Listener::Tcp(TcpListener::bind(s).await.with_context(|| format!("binding {s}"))?));
+            }
+            ListenAddr::Unix(p) => {
+                // Remove stale socket left from previous run, but not one of a running instance
+                if std::fs::symlink_metadata(p).is_ok_and(|m| std::os::unix::fs::FileTypeExt::is_socket(&m.file_type())) {
+                    match std::os::unix::net::UnixStream::connect(p) {
+                        Ok(_) => anyhow::bail!("Something is already listening on {p:?}"),
+                        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                            let _ = std::fs::remove_file(p);
+                        }
+                        // Let binding report the problem
+                        Err(_) => (),
+                    }
+                }
+                listeners.push(Listener::Unix(UnixListener::bind(p).with_context(|| format!("binding {p:?}"))?));
+            }
+            ListenAddr::Systemd => listeners.extend(systemd_listeners()?),
+        }
+    }
+    if listeners.is_empty() {
+        anyhow::bail!("No sockets to listen on");
+    }
+    Ok(listeners)
+}
+
+fn systemd_listeners() -> anyhow::Result<Vec<Listener>> {
+    let mut fds = listenfd::ListenFd::from_env();
+    if fds.len() == 0 {
+        anyhow::bail!("Asked to use systemd socket activation, but no sockets are passed in LISTEN_FDS");
+    }
+    let mut listeners = Vec::with_capacity(fds.len());
+    for i in 0..fds.len() {
+        let listener = match fds.take_tcp_listener(i) {
+            Ok(Some(l)) => {
+                l.set_nonblocking(true)?;
+                Listener::Tcp(TcpListener::from_std(l)?)
+            }
+            Ok(None) => continue,
+            Err(_) => match fds.take_unix_listener(i)? {
+                Some(l) => {
+                    l.set_nonblocking(true)?;
+                    Listener::Unix(UnixListener::from_std(l)?)
+                }
+                None => continue,
+            },
+        };
+        listeners.push(listener);
+    }
+    info!("Using {} sockets from systemd", listeners.len());
+    Ok(listeners)
+}
+
+/// Accepted connection from any kind of listener
+pub enum Connection {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            Connection::Unix(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            Connection::Unix(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(s) => Pin::new(s).poll_flush(cx),
+            Connection::Unix(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            Connection::Unix(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Tell systemd (if running as a `Type=notify` service) that we are ready to serve, and start watchdog pings if requested
+pub fn notify_ready() {
+    if let Err(e) = sd_notify::notify(false, &[sd_notify::NotifyState::Ready]) {
+        warn!("sd_notify READY: {e}");
+    }
+    let mut usec = 0;
+    if sd_notify::watchdog_enabled(false, &mut usec) && usec > 0 {
+        let interval = Duration::from_micros(usec) / 2;
+        info!("Pinging systemd watchdog every {interval:?}");
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                if let Err(e) = sd_notify::notify(false, &[sd_notify::NotifyState::Watchdog]) {
+                    error!("sd_notify WATCHDOG: {e}");
+                }
+            }
+        });
+    }
+}
+
+pub fn notify_reloading() {
+    if let Err(e) = sd_notify::notify(false, &[sd_notify::NotifyState::Reloading]) {
+        warn!("sd_notify RELOADING: {e}");
+    }
+}
+
+pub fn notify_stopping() {
+    if let Err(e) = sd_notify::notify(false, &[sd_notify::NotifyState::Stopping]) {
+        warn!("sd_notify STOPPING: {e}");
+    }
+}
@@ -1,19 +1,22 @@
+use argh::FromArgs;
 use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
 use axum::{
-    http::{header::CONTENT_SECURITY_POLICY, HeaderValue},
+    http::HeaderValue,
+    middleware,
     response::Redirect,
     routing::{get, get_service, post},
     Extension, Router, extract::DefaultBodyLimit,
 };
 use tower_http::services::ServeDir;
+use tracing::{error, info, warn};
 
 /// simple insecure HTTP server with anonymous file upload (including html/js upload and publication)
 #[derive(argh::FromArgs)]
 struct Opts {
-    /// socket address to bind TCP socket and listen for including HTTP requests
-    #[argh(positional)]
-    listen_socket: SocketAddr,
+    /// addresses to listen for incoming HTTP requests on: `IP:PORT` for TCP, `unix:PATH` for Unix socket or `systemd` for sockets passed by systemd. If none are specified, sockets passed by systemd are used
+    #[argh(positional, from_str_fn(listen::parse_listen_addr))]
+    listen_socket: Vec<listen::ListenAddr>,
 
     /// serve (and upload) files from this directory at /transient/
     #[argh(option, short = 't')]
@@ -31,6 +34,18 @@ struct Opts {
     #[argh(option, default = "10_000_000_000")]
     max_bytes: u64,
 
+    /// reject uploads that would leave less than this number of bytes free on the filesystem of the directory. Default is 100MB
+    #[argh(option, default = "100_000_000")]
+    reserved_space: u64,
+
+    /// rescan directories every this number of seconds to correct quota counters after external changes to files. Default is 600, 0 disables rescans
+    #[argh(option, default = "600")]
+    quota_rescan_secs: u64,
+
+    /// account disk blocks allocated to files instead of their apparent sizes for the byte quota
+    #[argh(switch)]
+    count_allocated_blocks: bool,
+
     /// time of day (UTC+0 timezone) to trigger the cleanup event on. Default is `00:00:00`
     #[argh(option, default = "time::Time::MIDNIGHT", from_str_fn(parsetime))]
     cleanup_time_utc: time::Time,
@@ -47,15 +62,207 @@ struct Opts {
     #[argh(option, default = "\"Duplo (permanent)\".to_owned()")]
     permanent_title: String,
 
+    /// show Markdown (`.md`) files in transient directory rendered by default; `?raw` gives the original. Without this, rendering is available with `?render`
+    #[argh(switch)]
+    transient_render_markdown: bool,
+
+    /// show Markdown (`.md`) files in permanent directory rendered by default
+    #[argh(switch)]
+    permanent_render_markdown: bool,
+
+    /// what to do when a file with the same name is uploaded to transient directory: `rename` (to `name (1).ext`, the default), `overwrite`, `reject` or `history` (overwrite, keeping the old file in history)
+    #[argh(option, from_str_fn(actions::parse_on_conflict), default = "actions::OnConflict::Rename")]
+    transient_on_conflict: actions::OnConflict,
+
+    /// what to do when a file with the same name is uploaded to permanent directory: `rename`, `overwrite`, `reject` or `history`
+    #[argh(option, from_str_fn(actions::parse_on_conflict), default = "actions::OnConflict::Rename")]
+    permanent_on_conflict: actions::OnConflict,
+
+    /// which symlinks in transient directory are served, listed and cleaned up: `deny` (the default), `within-share` (only ones resolving inside the directory) or `allow`. Device files, FIFOs and sockets are never served
+    #[argh(option, from_str_fn(symlinks::parse_symlink_policy), default = "symlinks::SymlinkPolicy::Deny")]
+    transient_symlinks: symlinks::SymlinkPolicy,
+
+    /// which symlinks in permanent directory are served and listed: `deny` (the default), `within-share` or `allow`
+    #[argh(option, from_str_fn(symlinks::parse_symlink_policy), default = "symlinks::SymlinkPolicy::Deny")]
+    permanent_symlinks: symlinks::SymlinkPolicy,
+
+    /// octal permission bits (e.g. `0640`, at most `0777`) for files created in transient directory, regardless of umask. Duplo never creates directories, so there is no mode for them
+    #[argh(option, from_str_fn(ownership::parse_mode))]
+    transient_file_mode: Option<u32>,
+
+    /// octal mode bits for files created in permanent directory
+    #[argh(option, from_str_fn(ownership::parse_mode))]
+    permanent_file_mode: Option<u32>,
+
+    /// group (name or ID) for files created in transient directory
+    #[argh(option, from_str_fn(ownership::parse_group))]
+    transient_group: Option<nix::unistd::Gid>,
+
+    /// group (name or ID) for files created in permanent directory
+    #[argh(option, from_str_fn(ownership::parse_group))]
+    permanent_group: Option<nix::unistd::Gid>,
+
     /// set this Content-Security-Policy header for served files
     #[argh(option, default = "\"default-src 'none'; img-src 'self' 'unsafe-inline'; style-src 'self' 'unsafe-inline'; script-src 'self' 'unsafe-inline' 'wasm-unsafe-eval'; connect-src 'none'; frame-ancestors 'none'\".to_owned()")]
     content_security_policy: String,
+
+    /// maximum number of uploads, text shares and removals a client address can do within `--client-window-secs`. Unlimited by default
+    #[argh(option)]
+    client_max_requests: Option<u64>,
+
+    /// maximum number of files a client address can create within `--client-window-secs`. Unlimited by default
+    #[argh(option)]
+    client_max_files: Option<u64>,
+
+    /// maximum number of bytes a client address can upload within `--client-window-secs`. Unlimited by default
+    #[argh(option)]
+    client_max_bytes: Option<u64>,
+
+    /// duration of time window for per-client limits, in seconds. Default is 3600
+    #[argh(option, default = "3600")]
+    client_window_secs: u64,
+
+    /// account IPv4 clients by subnets of this prefix length for per-client limits. Default is 32 (each address separately)
+    #[argh(option, default = "32")]
+    client_ipv4_prefix: u8,
+
+    /// account IPv6 clients by subnets of this prefix length for per-client limits. Default is 64
+    #[argh(option, default = "64")]
+    client_ipv6_prefix: u8,
+
+    /// cap total upload and (separately) download bandwidth to this number of bytes per second. Unlimited by default
+    #[argh(option)]
+    max_rate: Option<u64>,
+
+    /// cap upload and (separately) download bandwidth of transient directory to this number of bytes per second
+    #[argh(option)]
+    transient_max_rate: Option<u64>,
+
+    /// cap upload and (separately) download bandwidth of permanent directory to this number of bytes per second
+    #[argh(option)]
+    permanent_max_rate: Option<u64>,
+
+    /// maximum size of a single file uploaded to transient directory, in bytes. Unlimited by default
+    #[argh(option)]
+    transient_max_file_size: Option<u64>,
+
+    /// maximum size of a single file uploaded to permanent directory, in bytes. Unlimited by default
+    #[argh(option)]
+    permanent_max_file_size: Option<u64>,
+
+    /// cap upload and (separately) download bandwidth of each client address (or subnet, see `--client-ipv4-prefix`) to this number of bytes per second
+    #[argh(option)]
+    client_max_rate: Option<u64>,
+
+    /// maximum number of uploads in progress at the same time. Unlimited by default
+    #[argh(option)]
+    max_concurrent_uploads: Option<usize>,
+
+    /// maximum number of uploads in progress at the same time from one client address (or subnet). Unlimited by default
+    #[argh(option)]
+    client_max_concurrent_uploads: Option<usize>,
+
+    /// close connections that fail to send request headers within this number of seconds
+    #[argh(option)]
+    header_read_timeout_secs: Option<u64>,
+
+    /// abort uploads if no upload data arrives for this number of seconds
+    #[argh(option)]
+    body_read_timeout_secs: Option<u64>,
+
+    /// close connections that have no data transferred in either direction for this number of seconds
+    #[argh(option)]
+    idle_timeout_secs: Option<u64>,
+
+    /// abort requests that take longer than this number of seconds to handle (including receiving the upload)
+    #[argh(option)]
+    max_request_secs: Option<u64>,
+
+    /// read more options from this file, one per line (e.g. `--transient-title My files`); options given on the command line override ones from the file. On SIGHUP the file is re-read and titles, Content-Security-Policy, writers, per-directory rate and file size limits, Markdown rendering, collision handling, symlink policies, modes and groups of created files, per-client limits, bandwidth caps, quotas (including `--count-allocated-blocks`) and cleanup schedule are updated without restart
+    #[argh(option)]
+    config: Option<PathBuf>,
+
+    /// append JSON lines audit log of uploads, text shares, removals and cleanups to this file (`-` means stdout, which is the default)
+    #[argh(option)]
+    audit_log: Option<PathBuf>,
+
+    /// serve Prometheus metrics (quota usage, uploads, downloads, rejections, timeouts, cleanups, request latencies) at `/metrics` on this separate socket: `IP:PORT` or `unix:PATH`. Metrics are not available on the main listen sockets
+    #[argh(option, from_str_fn(listen::parse_listen_addr))]
+    metrics_socket: Option<listen::ListenAddr>,
+
+    /// on SIGTERM or SIGINT, wait up to this number of seconds for in-flight requests (e.g. uploads) to finish before exiting. Incomplete uploads are removed. Default is 30
+    #[argh(option, default = "30")]
+    shutdown_timeout_secs: u64,
+
+    /// serve HTTPS instead of HTTP using this PEM certificate chain file. Requires `--tls-key`. Reloaded on SIGHUP or when changed
+    #[argh(option)]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key file for `--tls-cert`
+    #[argh(option)]
+    tls_key: Option<PathBuf>,
+
+    /// request client certificates signed by CA certificates from this PEM file. Only clients with such certificates can upload, share text and remove files
+    #[argh(option)]
+    tls_client_ca: Option<PathBuf>,
+
+    /// comma-separated certificate names (subject CN, DNS or email SAN) allowed to modify transient directory. Any certificate signed by `--tls-client-ca` is allowed by default
+    #[argh(option)]
+    transient_writers: Option<String>,
+
+    /// comma-separated certificate names (subject CN, DNS or email SAN) allowed to modify permanent directory. Any certificate signed by `--tls-client-ca` is allowed by default
+    #[argh(option)]
+    permanent_writers: Option<String>,
+
+    /// additionally listen for plain HTTP requests on this socket address and redirect them to HTTPS
+    #[argh(option)]
+    https_redirect_socket: Option<SocketAddr>,
+
+    /// comma-separated IP addresses or `IP/PREFIX` subnets of reverse proxies whose `Forwarded` or `X-Forwarded-For` headers are trusted to tell client address. Use `unix` to trust clients connected over Unix sockets
+    #[argh(option)]
+    trusted_proxies: Option<String>,
+
+    /// expect HAProxy PROXY protocol (v1 or v2) header on connections from `--trusted-proxies`
+    #[argh(switch)]
+    proxy_protocol: bool,
+
+    /// serve everything under this URL path prefix (e.g. `/duplo`) instead of at the root, for reverse proxies that map a sub-path to Duplo
+    #[argh(option, from_str_fn(proxy::parse_url_prefix), default = "String::new()")]
+    url_prefix: String,
+
+    /// after binding sockets, restrict the process with Linux Landlock to accessing only the shared directories, reading `--config` file and reading files in the directories `--tls-cert` and `--tls-key` resolve to (so that renewed ones can be reloaded), also forbidding to bind or connect TCP sockets. `--config` then has to be modified in place to be reloaded, and user and group names in it have to be ones given at startup (or numeric group IDs), as the user database is not readable anymore. Symlinks leading outside of shared directories stop working
+    #[argh(switch)]
+    landlock: bool,
+
+    /// after binding sockets and loading TLS certificate and key, switch to this user (name or ID) and its groups. Requires starting as root
+    #[argh(option, from_str_fn(ownership::parse_user))]
+    user: Option<nix::unistd::User>,
 }
 
 mod actions;
+mod audit;
+mod bandwidth;
+mod body;
+mod clientlimits;
+mod config;
 mod disksize;
+mod downloads;
 mod embedded_resources;
 mod file_list;
+mod filename;
+mod health;
+mod highlight;
+mod history;
+mod listen;
+mod markdown;
+mod meta;
+mod metrics;
+mod ownership;
+mod proxy;
+mod sandbox;
+mod server;
+mod symlinks;
+mod tls;
 
 fn parsetime(x: &str) -> Result<time::Time, String> {
     let format = time::format_description::parse("[hour]:[minute]:[second]").unwrap();
@@ -63,21 +270,211 @@ fn parsetime(x: &str) -> Result<time::Time, String> {
 }
 
 struct SharedDirectory {
+    /// `transient` or `permanent`, for logs
+    name: &'static str,
     dir: PathBuf,
     title: String,
+    rate_limit: Option<bandwidth::RateLimit>,
+    max_file_size: Option<u64>,
+    write_access: tls::WriteAccess,
+    /// `--url-prefix`, for links to resources
+    url_prefix: String,
+    /// Added to responses with served files
+    content_security_policy: HeaderValue,
+    /// Serve Markdown files rendered unless `?raw` is requested
+    render_markdown: bool,
+    /// Handling of uploads and text shares with names of existing files
+    on_conflict: actions::OnConflict,
+    /// Which symlinks are followed
+    symlinks: symlinks::SymlinkPolicy,
+    /// Mode bits and group of created files
+    ownership: ownership::Ownership,
+}
+
+fn write_access(client_ca: bool, writers: Option<String>) -> anyhow::Result<tls::WriteAccess> {
+    Ok(match (client_ca, writers) {
+        (false, None) => tls::WriteAccess::Anyone,
+        (false, Some(_)) => anyhow::bail!("--transient-writers and --permanent-writers require --tls-client-ca"),
+        (true, None) => tls::WriteAccess::AnyCertificate,
+        (true, Some(w)) => tls::WriteAccess::Names(w.split(',').map(|x| x.trim().to_owned()).collect()),
+    })
+}
+
+/// Per-directory settings for transient and permanent directories
+fn shared_directory_settings(opts: &Opts) -> anyhow::Result<[SharedDirectory; 2]> {
+    let content_security_policy = HeaderValue::from_str(&opts.content_security_policy)?;
+    let client_ca = opts.tls_client_ca.is_some();
+    Ok([
+        SharedDirectory {
+            name: "transient",
+            dir: opts.transiet_directory.clone(),
+            title: opts.transient_title.clone(),
+            rate_limit: opts.transient_max_rate.map(bandwidth::RateLimit::new),
+            max_file_size: opts.transient_max_file_size,
+            write_access: write_access(client_ca, opts.transient_writers.clone())?,
+            url_prefix: opts.url_prefix.clone(),
+            content_security_policy: content_security_policy.clone(),
+            render_markdown: opts.transient_render_markdown,
+            on_conflict: opts.transient_on_conflict,
+            symlinks: opts.transient_symlinks,
+            ownership: ownership::Ownership {
+                mode: opts.transient_file_mode,
+                group: opts.transient_group,
+            },
+        },
+        SharedDirectory {
+            name: "permanent",
+            dir: opts.permanent_directory.clone(),
+            title: opts.permanent_title.clone(),
+            rate_limit: opts.permanent_max_rate.map(bandwidth::RateLimit::new),
+            max_file_size: opts.permanent_max_file_size,
+            write_access: write_access(client_ca, opts.permanent_writers.clone())?,
+            url_prefix: opts.url_prefix.clone(),
+            content_security_policy,
+            render_markdown: opts.permanent_render_markdown,
+            on_conflict: opts.permanent_on_conflict,
+            symlinks: opts.permanent_symlinks,
+            ownership: ownership::Ownership {
+                mode: opts.permanent_file_mode,
+                group: opts.permanent_group,
+            },
+        },
+    ])
+}
+
+/// Parse command line arguments together with ones from `--config` file; command line overrides the file
+fn parse_opts(args: &[String]) -> anyhow::Result<Result<Opts, argh::EarlyExit>> {
+    let mut args = args.to_vec();
+    if let Some(path) = config::config_path(&args) {
+        args = config::merge_args(&args, config::read_args(&path)?, &[("-t", "--transiet-directory"), ("-p", "--permanent-directory")]);
+    }
+    let cmd = std::path::Path::new(&args[0]).file_name().and_then(|x| x.to_str()).unwrap_or("duplo");
+    let args: Vec<&str> = args[1..].iter().map(String::as_str).collect();
+    Ok(Opts::from_args(&[cmd], &args))
+}
+
+fn client_limits_config(opts: &Opts) -> clientlimits::ClientLimitsConfig {
+    clientlimits::ClientLimitsConfig {
+        max_requests: opts.client_max_requests,
+        max_files: opts.client_max_files,
+        max_bytes: opts.client_max_bytes,
+        window: Duration::from_secs(opts.client_window_secs),
+        ipv4_prefix: opts.client_ipv4_prefix,
+        ipv6_prefix: opts.client_ipv6_prefix,
+        max_concurrent_uploads: opts.max_concurrent_uploads,
+        client_max_concurrent_uploads: opts.client_max_concurrent_uploads,
+        body_read_timeout: opts.body_read_timeout_secs.map(Duration::from_secs),
+    }
+}
+
+fn bandwidth_config(opts: &Opts) -> bandwidth::BandwidthConfig {
+    bandwidth::BandwidthConfig {
+        global_rate: opts.max_rate,
+        client_rate: opts.client_max_rate,
+        ipv4_prefix: opts.client_ipv4_prefix,
+        ipv6_prefix: opts.client_ipv6_prefix,
+    }
+}
+
+/// Re-read `--config` file and apply settings that can be changed without restart
+fn reload_config(
+    args: &[String],
+    shares: &[Arc<config::Reloadable<SharedDirectory>>; 2],
+    quotas: &disksize::Quotas,
+    cleanup_schedule: &std::sync::mpsc::Sender<disksize::CleanupSchedule>,
+    client_limits: &clientlimits::ClientLimits,
+    bandwidth: &bandwidth::Bandwidth,
+) -> anyhow::Result<()> {
+    let opts = match parse_opts(args)? {
+        Ok(x) => x,
+        Err(e) => anyhow::bail!("{}", e.output.trim()),
+    };
+    let new_shares = shared_directory_settings(&opts)?;
+    for (share, mut new) in shares.iter().zip(new_shares) {
+        let dir = &share.get().dir;
+        if new.dir != *dir {
+            warn!("Changing directories requires restart, keeping {dir:?}");
+            new.dir = dir.clone();
+        }
+        share.set(new);
+    }
+    quotas.set_limits(opts.max_files, opts.max_bytes, opts.reserved_space);
+    if quotas.count_blocks.swap(opts.count_allocated_blocks, std::sync::atomic::Ordering::SeqCst) != opts.count_allocated_blocks {
+        warn!("Byte quota accounting mode changed, usage counter gets corrected by the next rescan");
+    }
+    client_limits.set_config(client_limits_config(&opts));
+    bandwidth.set_config(bandwidth_config(&opts));
+    let _ = cleanup_schedule.send(disksize::CleanupSchedule {
+        time: opts.cleanup_time_utc,
+        max_age: Duration::from_secs(3600 * opts.cleanup_maxhours),
+    });
+    Ok(())
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> anyhow::Result<()> {
-    let opts: Opts = argh::from_env();
+    let args: Vec<String> = std::env::args().collect();
+    let mut opts: Opts = match parse_opts(&args)? {
+        Ok(x) => x,
+        Err(e) => {
+            // Same as `argh::from_env`
+            match e.status {
+                Ok(()) => println!("{}", e.output),
+                Err(()) => eprintln!("{}\nRun {} --help for more information.", e.output, args[0].rsplit('/').next().unwrap_or("duplo")),
+            }
+            std::process::exit(match e.status {
+                Ok(()) => 0,
+                Err(()) => 1,
+            });
+        }
+    };
     tracing_subscriber::fmt::init();
+    // Declared early to be dropped last, after everything that records events
+    let _audit_writer = audit::init(opts.audit_log.as_deref())?;
 
-    let listen_socket = opts.listen_socket;
-    let transient_directory_ = opts.transiet_directory.clone();
+    let mut listen_socket = std::mem::take(&mut opts.listen_socket);
+    if listen_socket.is_empty() {
+        listen_socket.push(listen::ListenAddr::Systemd);
+    }
+    let shared_directories = vec![opts.transiet_directory.clone(), opts.permanent_directory.clone()];
+    for dir in &shared_directories {
+        actions::remove_staging_files(dir);
+    }
 
-    let quotas = disksize::Quotas::new(opts.max_files, opts.max_bytes);
-    quotas.scan_and_add(&opts.permanent_directory)?;
-    quotas.scan_and_add(&opts.transiet_directory)?;
+    let listeners = listen::bind_all(&listen_socket).await?;
+    let redirect_listener = match opts.https_redirect_socket {
+        Some(s) => Some(listen::Listener::Tcp(tokio::net::TcpListener::bind(s).await?)),
+        None => None,
+    };
+    let metrics_listener = match opts.metrics_socket.take() {
+        Some(listen::ListenAddr::Systemd) => anyhow::bail!("--metrics-socket must be `IP:PORT` or `unix:PATH`"),
+        Some(a) => listen::bind_all(&[a]).await?.pop(),
+        None => None,
+    };
+    // Key is often readable only by root
+    let tls = match (&opts.tls_cert, &opts.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::acceptor(tls::TlsFiles {
+            cert: cert.clone(),
+            key: key.clone(),
+            client_ca: opts.tls_client_ca.clone(),
+        })?),
+        (None, None) if opts.tls_client_ca.is_some() => anyhow::bail!("--tls-client-ca requires --tls-cert and --tls-key"),
+        (None, None) => None,
+        _ => anyhow::bail!("--tls-cert and --tls-key must be specified together"),
+    };
+    if let Some(user) = &opts.user {
+        ownership::drop_privileges(user)?;
+    }
+    if opts.landlock {
+        let read_files: Vec<PathBuf> = opts.config.iter().cloned().collect();
+        // Certificate and key are reloaded when renewed, which usually puts new files next to the old ones
+        let read_dirs = sandbox::target_dirs([&opts.tls_cert, &opts.tls_key].into_iter().flatten().map(PathBuf::as_path));
+        sandbox::landlock(&shared_directories, &read_files, &read_dirs)?;
+    }
+
+    let quotas = disksize::Quotas::new(opts.max_files, opts.max_bytes, opts.reserved_space, opts.count_allocated_blocks);
+    let shares = vec![("transient", opts.transiet_directory.clone()), ("permanent", opts.permanent_directory.clone())];
+    quotas.scan_and_add(&shares)?;
     println!(
         "Started, serving {} files and {} bytes",
         quotas.files.get(),
@@ -86,57 +483,221 @@ async fn main() -> anyhow::Result<()> {
     let quotas = Arc::new(quotas);
     let quotas_ = quotas.clone();
 
+    // Dropping these senders tells background threads to stop
+    let mut background_stops = vec![];
+    let mut background_threads = vec![];
+
+    if opts.quota_rescan_secs > 0 {
+        let quotas = quotas.clone();
+        let interval = Duration::from_secs(opts.quota_rescan_secs);
+        let (stop_tx, stop) = std::sync::mpsc::channel();
+        background_stops.push(stop_tx);
+        background_threads.push(std::thread::spawn(move || disksize::reconcile_task(&shares, interval, quotas, stop)));
+    }
+
+    let client_limits = Arc::new(clientlimits::ClientLimits::new(client_limits_config(&opts)));
+    let bandwidth = Arc::new(bandwidth::Bandwidth::new(bandwidth_config(&opts)));
+
+    let [transient_share, permanent_share] = shared_directory_settings(&opts)?.map(|x| Arc::new(config::Reloadable::new(x)));
+
     let uploader = Router::new().route("/", post(actions::upload)).layer(DefaultBodyLimit::disable());
 
     let app = Router::new()
         .route("/", get(file_list::serve_view))
         .route("/shareText/", post(actions::share_text))
         .route("/remove/", post(actions::remove))
-        .nest("/upload/", uploader);
-
-    let security_header_for_content = tower_http::set_header::response::SetResponseHeaderLayer::appending(CONTENT_SECURITY_POLICY, HeaderValue::from_str(
-        &opts.content_security_policy
-    )?);
+        .route("/editText/", post(actions::edit_text))
+        .route("/.view/:file", get(highlight::serve_text_view))
+        .route("/.diff/:file", get(history::serve_diff))
+        .nest("/upload/", uploader)
+        .layer(Extension(client_limits.clone()));
+    let download_limits = Arc::new(downloads::DownloadLimits::new(quotas.clone()));
 
 
     let app_transient = app
         .clone()
         .fallback_service(
             get_service(ServeDir::new(opts.transiet_directory.clone()))
-            .layer(security_header_for_content.clone())
+            .layer(middleware::from_fn(file_list::content_security_policy))
+            .layer(middleware::from_fn(markdown::render_markdown))
+            .layer(middleware::from_fn(bandwidth::throttle_downloads))
+            .layer(middleware::from_fn(metrics::count_downloads))
+            .layer(middleware::from_fn(downloads::limit_downloads))
+            .layer(middleware::from_fn(symlinks::enforce_policy))
             ,
         )
-        .layer(Extension(Arc::new(SharedDirectory{dir: opts.transiet_directory, title: opts.transient_title})))
+        .layer(middleware::from_fn_with_state(transient_share.clone(), config::current))
+        .layer(Extension(bandwidth.clone()))
+        .layer(Extension(download_limits.clone()))
         .with_state(quotas.clone())
         ;
     let app_permanent = app
         .fallback_service(
             get_service(ServeDir::new(opts.permanent_directory.clone()))
-            .layer(security_header_for_content),
+            .layer(middleware::from_fn(file_list::content_security_policy))
+            .layer(middleware::from_fn(markdown::render_markdown))
+            .layer(middleware::from_fn(bandwidth::throttle_downloads))
+            .layer(middleware::from_fn(metrics::count_downloads))
+            .layer(middleware::from_fn(downloads::limit_downloads))
+            .layer(middleware::from_fn(symlinks::enforce_policy)),
         )
-        .layer(Extension(Arc::new(SharedDirectory{dir: opts.permanent_directory, title: opts.permanent_title})))
-        .with_state(quotas);
+        .layer(middleware::from_fn_with_state(permanent_share.clone(), config::current))
+        .layer(Extension(bandwidth.clone()))
+        .layer(Extension(download_limits))
+        .with_state(quotas.clone());
 
-    std::thread::spawn(move || {
+    // Dropping the sender stops the cleanup thread
+    let (cleanup_schedule, schedule_updates) = std::sync::mpsc::channel();
+    let schedule = disksize::CleanupSchedule {
+        time: opts.cleanup_time_utc,
+        max_age: Duration::from_secs(3600*opts.cleanup_maxhours),
+    };
+    let cleanup_alive = Arc::new(());
+    let readiness = Arc::new(health::Readiness {
+        shares: [transient_share.clone(), permanent_share.clone()],
+        quotas: quotas.clone(),
+        cleanup_alive: Arc::downgrade(&cleanup_alive),
+    });
+    let transient_share_ = transient_share.clone();
+    background_threads.push(std::thread::spawn(move || {
+        // Dropped also when the thread panics; errors exit the process
+        let _alive = cleanup_alive;
         let Err(e) = disksize::cleanup_task(
-            &transient_directory_,
-            opts.cleanup_time_utc,
-            Duration::from_secs(3600*opts.cleanup_maxhours),
+            &transient_share_,
+            schedule,
             quotas_,
+            schedule_updates,
         ) else {return} ;
         eprintln!("Error from cleanup task: {e}");
         std::process::exit(4);
-    });
+    }));
 
+    let transient_url = format!("{}/transient/", opts.url_prefix);
+    let to_transient = get(move || async move { Redirect::permanent(&transient_url) });
     let routes = Router::new()
-        .route("/", get(|| async { Redirect::permanent("/transient/") }))
+        .route("/", to_transient.clone())
         .nest_service("/transient", app_transient)
         .nest_service("/permanent", app_permanent)
         .route("/res/*path", get(embedded_resources::serve_embedded))
-        .layer(tower_http::trace::TraceLayer::new_for_http());
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz).layer(Extension(readiness)));
+    let routes = if opts.url_prefix.is_empty() {
+        routes
+    } else {
+        // Nested `/` route only matches the prefix without trailing slash
+        Router::new()
+            .nest(&opts.url_prefix, routes)
+            .route(&format!("{}/", opts.url_prefix), to_transient)
+    };
+    let routes = match opts.max_request_secs {
+        Some(secs) => routes.layer(middleware::from_fn_with_state(Duration::from_secs(secs), server::limit_request_duration)),
+        None => routes,
+    };
+    let trusted_proxies = match opts.trusted_proxies {
+        Some(list) => Some(Arc::new(proxy::TrustedProxies::parse(&list)?)),
+        None if opts.proxy_protocol => anyhow::bail!("--proxy-protocol requires --trusted-proxies"),
+        None => None,
+    };
+    let routes = match &trusted_proxies {
+        Some(proxies) => routes.layer(middleware::from_fn_with_state(proxies.clone(), proxy::real_client_addr)),
+        None => routes,
+    };
+    let proxy_protocol = trusted_proxies.filter(|_| opts.proxy_protocol);
+    let routes = routes.layer(tower_http::trace::TraceLayer::new_for_http().on_response(
+        |res: &axum::response::Response, latency: Duration, _span: &tracing::Span| {
+            tracing::debug!(status = res.status().as_u16(), ?latency, "finished processing request");
+            metrics::request_finished(res.status().as_u16(), latency);
+        },
+    ));
+
+    let limits = server::ConnectionLimits {
+        header_read_timeout: opts.header_read_timeout_secs.map(Duration::from_secs),
+        idle_timeout: opts.idle_timeout_secs.map(Duration::from_secs),
+    };
+
+    let (shutdown_tx, shutdown) = tokio::sync::watch::channel(false);
+
+    if let Some(redirect_listener) = redirect_listener {
+        if tls.is_none() {
+            anyhow::bail!("--https-redirect-socket requires --tls-cert and --tls-key");
+        }
+        let app = tls::redirect_app(listeners.iter().find_map(listen::Listener::port).unwrap_or(443));
+        let proxy_protocol = proxy_protocol.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            let Err(e) = server::serve(redirect_listener, app, limits, None, proxy_protocol, shutdown).await else {return};
+            eprintln!("Error from HTTPS redirect listener: {e}");
+            std::process::exit(4);
+        });
+    }
+
+    if let Some(metrics_listener) = metrics_listener {
+        let app = Router::new()
+            .route("/metrics", get(metrics::serve_metrics))
+            .layer(Extension(quotas.clone()))
+            .layer(Extension(Arc::new([transient_share.clone(), permanent_share.clone()])));
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            let Err(e) = server::serve(metrics_listener, app, limits, None, None, shutdown).await else {return};
+            eprintln!("Error from metrics listener: {e}");
+            std::process::exit(4);
+        });
+    }
+
+    let servers = futures::future::try_join_all(
+        listeners
+            .into_iter()
+            .map(|l| server::serve(l, routes.clone(), limits, tls.clone(), proxy_protocol.clone(), shutdown.clone())),
+    );
+    drop(shutdown);
+    tokio::pin!(servers);
+    let shutdown_signal = server::shutdown_signal()?;
+    tokio::pin!(shutdown_signal);
+    // Without configuration file there is nothing to reload, and SIGHUP keeps its default action
+    let mut sighup = match opts.config {
+        Some(_) => Some(tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?),
+        None => None,
+    };
+    listen::notify_ready();
+    loop {
+        tokio::select! {
+            ret = &mut servers => return ret.map(|_| ()),
+            () = &mut shutdown_signal => break,
+            _ = async {
+                match &mut sighup {
+                    Some(s) => s.recv().await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                listen::notify_reloading();
+                let shares = [transient_share.clone(), permanent_share.clone()];
+                match reload_config(&args, &shares, &quotas, &cleanup_schedule, &client_limits, &bandwidth) {
+                    Ok(()) => info!("Reloaded configuration from {:?}", opts.config.as_ref().unwrap()),
+                    Err(e) => error!("Failed to reload configuration, continuing with the old one: {e:#}"),
+                }
+                listen::notify_ready();
+            }
+        }
+    }
+    listen::notify_stopping();
+
+    // Stop accepting connections and let the existing ones finish their requests
+    let _ = shutdown_tx.send(true);
+    let timeout = Duration::from_secs(opts.shutdown_timeout_secs);
+    // Listeners need to be polled to notice the shutdown and drop their receivers
+    let drained = async {
+        let _ = servers.await;
+        shutdown_tx.closed().await;
+    };
+    if tokio::time::timeout(timeout, drained).await.is_err() {
+        // Staging files of the uploads get removed when runtime drops their tasks
+        warn!("Some requests are still in progress after {timeout:?}, aborting them");
+    }
 
-    axum::Server::try_bind(&listen_socket)?
-        .serve(routes.into_make_service_with_connect_info::<SocketAddr>())
-        .await?;
+    drop(background_stops);
+    drop(cleanup_schedule);
+    for t in background_threads {
+        let _ = t.join();
+    }
     Ok(())
 }
@@ -0,0 +1,127 @@
+use std::{
+    os::unix::ffi::OsStrExt,
+    path::{Component, Path},
+    sync::Arc,
+};
+
+use askama::Template;
+use askama_axum::IntoResponse;
+use axum::{
+    http::{Method, Request},
+    middleware::Next,
+    response::Response,
+    Extension,
+};
+use pulldown_cmark::{Options, Parser};
+
+use crate::{file_list, SharedDirectory};
+
+/// Larger files are served raw instead of being rendered
+const MAX_RENDERED_SIZE: u64 = 2 * 1024 * 1024;
+
+#[derive(Template)]
+#[template(path = "markdown.html")]
+pub struct MarkdownTemplate {
+    pub name: String,
+    /// Sanitized HTML
+    pub html: String,
+    /// Prepended to absolute links
+    pub url_prefix: String,
+}
+
+pub fn is_markdown_name(x: &str) -> bool {
+    let x = x.to_ascii_lowercase();
+    x.ends_with(".md") || x.ends_with(".markdown")
+}
+
+/// Convert CommonMark (with tables, strikethrough and task lists) to HTML that is safe to embed in a page
+fn render(text: &str) -> String {
+    let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS;
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, Parser::new_ext(text, options));
+    ammonia::clean(&html)
+}
+
+/// Middleware for the `ServeDir` fallback that shows Markdown files rendered within a page,
+/// when requested with `?render` or by default if the directory is configured so (and `?raw` is not requested)
+pub(crate) async fn render_markdown<B>(
+    Extension(shared_dir): Extension<Arc<SharedDirectory>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let query = req.uri().query().unwrap_or("");
+    let has_param = |p: &str| query.split('&').any(|x| x == p || x.starts_with(&format!("{p}=")));
+    let wanted = if shared_dir.render_markdown { !has_param("raw") } else { has_param("render") };
+    if req.method() != Method::GET || !wanted {
+        return next.run(req).await;
+    }
+    let name = percent_encoding::percent_decode_str(req.uri().path().trim_start_matches('/')).decode_utf8();
+    let Ok(name) = name else { return next.run(req).await };
+    // Checked here rather than relying on other layers to reject `..`, absolute paths and hidden files
+    let plain = |c: Component| matches!(c, Component::Normal(x) if !x.as_bytes().starts_with(b"."));
+    if !is_markdown_name(&name) || !Path::new(&*name).components().all(plain) {
+        return next.run(req).await;
+    }
+    let path = shared_dir.dir.join(&*name);
+    let Ok(metadata) = tokio::fs::metadata(&path).await else { return next.run(req).await };
+    if !metadata.is_file() || metadata.len() > MAX_RENDERED_SIZE {
+        return next.run(req).await;
+    }
+    let Ok(data) = tokio::fs::read(&path).await else { return next.run(req).await };
+    let Ok(html) = tokio::task::spawn_blocking(move || render(&String::from_utf8_lossy(&data))).await else {
+        return next.run(req).await;
+    };
+    let mut response = MarkdownTemplate {
+        name: name.rsplit('/').next().unwrap_or_default().to_owned(),
+        html,
+        url_prefix: shared_dir.url_prefix.clone(),
+    }
+    .into_response();
+    file_list::page_headers(&mut response);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render;
+
+    #[test]
+    fn renders_markdown() {
+        let html = render("# Title\n\n*a* | b\n--|--\n1 | ~~2~~\n\n- [x] done\n");
+        assert!(html.contains("<h1>Title</h1>"));
+        assert!(html.contains("<em>a</em>"));
+        assert!(html.contains("<table>"));
+        assert!(html.contains("<del>2</del>"));
+        assert!(html.contains("done"));
+    }
+
+    #[test]
+    fn strips_scripts() {
+        let html = render("text\n\n<script>alert(1)</script>\n\ninline <script>alert(2)</script> html");
+        assert!(!html.contains("<script"));
+        assert!(!html.contains("alert"));
+    }
+
+    #[test]
+    fn strips_javascript_links() {
+        for md in ["[click](javascript:alert(1))", "<a href=\"javascript:alert(1)\">click</a>", "[click](JaVaScRiPt:alert(1))", "![img](javascript:alert(1))"] {
+            let html = render(md);
+            assert!(!html.to_ascii_lowercase().contains("javascript:"), "{md}: {html}");
+        }
+        assert!(render("[ok](https://example.com/)").contains("href=\"https://example.com/\""));
+    }
+
+    #[test]
+    fn strips_event_handlers() {
+        for md in [
+            "<img src=\"x.png\" onerror=\"alert(1)\">",
+            "<p onclick=\"alert(1)\">text</p>",
+            "<a href=\"x\" onmouseover=\"alert(1)\">link</a>",
+            "<svg onload=\"alert(1)\"></svg>",
+        ] {
+            let html = render(md);
+            assert!(!html.contains("alert"), "{md}: {html}");
+            assert!(!html.to_ascii_lowercase().contains(" on"), "{md}: {html}");
+        }
+    }
+}
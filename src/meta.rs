@@ -0,0 +1,158 @@
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering::SeqCst},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::disksize::Quotas;
+
+/// Metadata of a shared file is kept in hidden `.NAME.meta` file next to it
+const META_SUFFIX: &str = ".meta";
+/// Previous versions of edited text shares are kept in hidden `.NAME.N.version` files
+const VERSION_SUFFIX: &str = ".version";
+
+/// Extra information about a shared file that does not fit in the file itself
+#[derive(Serialize, Deserialize, Default)]
+pub struct FileMeta {
+    /// Syntax highlighting language, as syntect syntax name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    /// Delete the file after this many completed downloads (or views)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_downloads: Option<u32>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub downloads: u32,
+    /// Version of the current content, starting from 1. Previous ones are in the history.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub version: u32,
+}
+
+fn is_zero(x: &u32) -> bool {
+    *x == 0
+}
+
+impl FileMeta {
+    pub fn downloads_left(&self) -> Option<u32> {
+        self.max_downloads.map(|max| max.saturating_sub(self.downloads))
+    }
+
+    pub fn current_version(&self) -> u32 {
+        self.version.max(1)
+    }
+}
+
+/// Whether `x` is a metadata or history file rather than a shared file
+pub fn is_meta_name(x: &str) -> bool {
+    owner_name(x).is_some()
+}
+
+/// Whether file named `x` is accounted in files quota. Metadata files are not (only in bytes quota),
+/// so that setting a download limit or language does not use up a file slot.
+pub fn counts_as_file(x: &str) -> bool {
+    !x.strip_prefix('.').is_some_and(|x| x.ends_with(META_SUFFIX))
+}
+
+/// Name of the shared file the metadata or history file `x` belongs to
+pub fn owner_name(x: &str) -> Option<&str> {
+    let x = x.strip_prefix('.')?;
+    if let Some(owner) = x.strip_suffix(META_SUFFIX) {
+        return Some(owner);
+    }
+    let (owner, n) = x.strip_suffix(VERSION_SUFFIX)?.rsplit_once('.')?;
+    n.parse::<u32>().ok().map(|_| owner)
+}
+
+/// Where previous version `n` of `file` is kept
+pub fn version_path(file: &Path, n: u32) -> Option<PathBuf> {
+    let name = file.file_name()?.to_str()?;
+    Some(file.with_file_name(format!(".{name}.{n}{VERSION_SUFFIX}")))
+}
+
+fn meta_path(file: &Path) -> Option<PathBuf> {
+    let name = file.file_name()?.to_str()?;
+    Some(file.with_file_name(format!(".{name}{META_SUFFIX}")))
+}
+
+/// Metadata of `file`, or default one if it has none or it is unreadable
+pub fn load(file: &Path) -> FileMeta {
+    let Some(path) = meta_path(file) else { return FileMeta::default() };
+    match std::fs::read(&path) {
+        Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+            warn!("Malformed metadata file {path:?}: {e}");
+            FileMeta::default()
+        }),
+        Err(e) if e.kind() == ErrorKind::NotFound => FileMeta::default(),
+        Err(e) => {
+            warn!("Cannot read metadata file {path:?}: {e}");
+            FileMeta::default()
+        }
+    }
+}
+
+/// Write metadata of `file`, accounting the metadata file in bytes quota.
+/// It is small, so it is written even if quotas are already full.
+/// Written into a temporary file first and renamed into place, so readers never see it half-written.
+pub fn save(file: &Path, meta: &FileMeta, quotas: &Quotas) -> std::io::Result<()> {
+    static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+    let Some(path) = meta_path(file) else { return Err(ErrorKind::InvalidInput.into()) };
+    let data = serde_json::to_vec(meta)?;
+    // Ends like a staging file, so that leftovers are removed at startup
+    let tmp = path.with_file_name(format!("{}.{}.uploading", path.file_name().unwrap().to_string_lossy(), SEQUENCE.fetch_add(1, SeqCst)));
+    let old = std::fs::metadata(&path).ok();
+    if let Err(e) = std::fs::write(&tmp, &data).and_then(|()| std::fs::rename(&tmp, &path)) {
+        _ = std::fs::remove_file(&tmp);
+        return Err(e);
+    }
+    if let Some(m) = old {
+        quotas.bytes.reduce(quotas.file_size(&m));
+    }
+    if let Ok(m) = std::fs::metadata(&path) {
+        quotas.bytes.bump(quotas.file_size(&m));
+    }
+    Ok(())
+}
+
+/// Remove metadata and history of `file` if there are any, updating quota counters
+pub fn remove(file: &Path, quotas: &Quotas) {
+    let versions = load(file).current_version();
+    let sidecars = (1..versions).filter_map(|n| version_path(file, n)).chain(meta_path(file));
+    for path in sidecars {
+        let Ok(m) = std::fs::symlink_metadata(&path) else { continue };
+        match std::fs::remove_file(&path) {
+            Ok(()) => {
+                if path.file_name().and_then(|x| x.to_str()).is_some_and(counts_as_file) {
+                    quotas.files.reduce(1);
+                }
+                quotas.bytes.reduce(quotas.file_size(&m));
+            }
+            Err(e) => warn!("Cannot remove metadata file {path:?}: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{counts_as_file, owner_name};
+
+    #[test]
+    fn owner_names() {
+        assert_eq!(owner_name(".notes.txt.meta"), Some("notes.txt"));
+        assert_eq!(owner_name(".notes.txt.3.version"), Some("notes.txt"));
+        assert_eq!(owner_name("..meta"), Some(""));
+        assert_eq!(owner_name("notes.txt.meta"), None);
+        assert_eq!(owner_name(".notes.txt.x.version"), None);
+        assert_eq!(owner_name(".notes.version"), None);
+        assert_eq!(owner_name(".notes.txt"), None);
+        assert_eq!(owner_name(".notes.txt.1.uploading"), None);
+    }
+
+    #[test]
+    fn metadata_is_not_counted_as_file() {
+        assert!(!counts_as_file(".notes.txt.meta"));
+        assert!(counts_as_file(".notes.txt.1.version"));
+        assert!(counts_as_file("notes.meta"));
+    }
+}
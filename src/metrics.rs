@@ -0,0 +1,318 @@
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering::SeqCst},
+        Arc,
+    },
+    time::Duration,
+};
+
+use axum::{
+    http::{header::CONTENT_TYPE, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+
+use crate::{config::Reloadable, disksize::Quotas, SharedDirectory};
+
+/// Upper bounds of request latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 12] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0, 30.0, 60.0, 300.0];
+
+/// Reasons of rejected requests, matching audit log outcomes
+const REJECTION_REASONS: [&str; 12] = [
+    "forbidden",
+    "throttled",
+    "too_many_uploads",
+    "too_large",
+    "quota_exceeded",
+    "disk_full",
+    "bad_name",
+    "bad_request",
+    "name_conflict",
+    "download_limited",
+    "not_found",
+    "failed",
+];
+
+/// Timeouts that close connections or abort requests
+#[derive(Clone, Copy)]
+pub enum Timeout {
+    /// Request headers, TLS handshake or PROXY protocol header not received in time
+    Header,
+    Idle,
+    /// Next chunk of upload body not received in time
+    Body,
+    /// Request handling took too long
+    Request,
+}
+
+const TIMEOUT_KINDS: [&str; 4] = ["header", "idle", "body", "request"];
+
+#[derive(Clone, Copy)]
+enum Share {
+    Transient,
+    Permanent,
+}
+
+impl Share {
+    const ALL: [Share; 2] = [Share::Transient, Share::Permanent];
+
+    fn from_name(name: &str) -> Option<Share> {
+        match name {
+            "transient" => Some(Share::Transient),
+            "permanent" => Some(Share::Permanent),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Share::Transient => "transient",
+            Share::Permanent => "permanent",
+        }
+    }
+}
+
+/// Counter with separate values for transient and permanent directories
+#[derive(Default)]
+struct PerShare([AtomicU64; Share::ALL.len()]);
+
+impl PerShare {
+    fn add(&self, share: Share, n: u64) {
+        self.0[share as usize].fetch_add(n, SeqCst);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter");
+        for (share, v) in Share::ALL.iter().zip(&self.0) {
+            let _ = writeln!(out, "{name}{{share=\"{}\"}} {}", share.name(), v.load(SeqCst));
+        }
+    }
+}
+
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, d: Duration) {
+        let secs = d.as_secs_f64();
+        for (bound, b) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            if secs <= *bound {
+                b.fetch_add(1, SeqCst);
+            }
+        }
+        self.count.fetch_add(1, SeqCst);
+        self.sum_micros.fetch_add(d.as_micros() as u64, SeqCst);
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        for (bound, b) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{bound}\"}} {}", b.load(SeqCst));
+        }
+        let count = self.count.load(SeqCst);
+        let _ = writeln!(out, "{name}_bucket{{{labels},le=\"+Inf\"}} {count}");
+        let _ = writeln!(out, "{name}_sum{{{labels}}} {}", self.sum_micros.load(SeqCst) as f64 / 1e6);
+        let _ = writeln!(out, "{name}_count{{{labels}}} {count}");
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram {
+            buckets: Default::default(),
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Metrics {
+    uploads: PerShare,
+    upload_bytes: PerShare,
+    text_shares: PerShare,
+    removals: PerShare,
+    downloads: PerShare,
+    download_bytes: PerShare,
+    uploads_in_flight: AtomicU64,
+    rejections: [AtomicU64; REJECTION_REASONS.len()],
+    timeouts: [AtomicU64; TIMEOUT_KINDS.len()],
+    quota_drift_corrections: AtomicU64,
+    cleanup_runs: AtomicU64,
+    cleanup_last_duration_micros: AtomicU64,
+    cleanup_duration_micros: AtomicU64,
+    cleanup_removed_files: AtomicU64,
+    cleanup_removed_bytes: AtomicU64,
+    /// Request latencies by status code class: 1xx..5xx
+    latency: [Histogram; 5],
+}
+
+static METRICS: std::sync::OnceLock<Metrics> = std::sync::OnceLock::new();
+
+fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::default)
+}
+
+/// Account an action from the audit log
+pub fn action(action: &str, outcome: &str, share: Option<&str>, size: Option<u64>) {
+    let m = metrics();
+    if outcome != "ok" {
+        if let Some(i) = REJECTION_REASONS.iter().position(|r| *r == outcome) {
+            m.rejections[i].fetch_add(1, SeqCst);
+        }
+        return;
+    }
+    let Some(share) = share.and_then(Share::from_name) else { return };
+    match action {
+        "upload" => {
+            m.uploads.add(share, 1);
+            m.upload_bytes.add(share, size.unwrap_or(0));
+        }
+        "share_text" => m.text_shares.add(share, 1),
+        "remove" => m.removals.add(share, 1),
+        _ => (),
+    }
+}
+
+pub fn timed_out(kind: Timeout) {
+    metrics().timeouts[kind as usize].fetch_add(1, SeqCst);
+}
+
+/// Counts an upload as in flight until dropped
+pub struct InFlightUpload(());
+
+impl InFlightUpload {
+    pub fn new() -> InFlightUpload {
+        metrics().uploads_in_flight.fetch_add(1, SeqCst);
+        InFlightUpload(())
+    }
+}
+
+impl Drop for InFlightUpload {
+    fn drop(&mut self) {
+        metrics().uploads_in_flight.fetch_sub(1, SeqCst);
+    }
+}
+
+pub fn quota_drift_corrected() {
+    metrics().quota_drift_corrections.fetch_add(1, SeqCst);
+}
+
+pub fn cleanup_finished(duration: Duration, files_removed: u64, bytes_removed: u64) {
+    let m = metrics();
+    m.cleanup_runs.fetch_add(1, SeqCst);
+    m.cleanup_last_duration_micros.store(duration.as_micros() as u64, SeqCst);
+    m.cleanup_duration_micros.fetch_add(duration.as_micros() as u64, SeqCst);
+    m.cleanup_removed_files.fetch_add(files_removed, SeqCst);
+    m.cleanup_removed_bytes.fetch_add(bytes_removed, SeqCst);
+}
+
+/// For `TraceLayer::on_response`
+pub fn request_finished(status: u16, latency: Duration) {
+    let class = usize::from(status / 100).clamp(1, 5) - 1;
+    metrics().latency[class].observe(latency);
+}
+
+/// Middleware for the `ServeDir` fallback that counts successful downloads and bytes sent
+pub(crate) async fn count_downloads<B>(
+    Extension(shared_dir): Extension<Arc<SharedDirectory>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let response = next.run(req).await;
+    if !response.status().is_success() {
+        return response;
+    }
+    let Some(share) = Share::from_name(shared_dir.name) else {
+        return response;
+    };
+    metrics().downloads.add(share, 1);
+    let count_bytes = move |len| {
+        metrics().download_bytes.add(share, len);
+        async {}
+    };
+    response.map(|body| crate::body::observe(body, None, count_bytes, || ()))
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: impl std::fmt::Display) {
+    let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}");
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: impl std::fmt::Display) {
+    let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter\n{name} {value}");
+}
+
+/// Handler for `/metrics` in Prometheus text format
+pub async fn serve_metrics(
+    Extension(quotas): Extension<Arc<Quotas>>,
+    Extension(shares): Extension<Arc<[Arc<Reloadable<SharedDirectory>>; 2]>>,
+) -> Response {
+    let m = metrics();
+    let mut out = String::new();
+    gauge(&mut out, "duplo_quota_files_used", "Number of files counted in the quota", quotas.files.get());
+    gauge(&mut out, "duplo_quota_files_allowed", "Maximum number of files", quotas.files.allowed());
+    gauge(&mut out, "duplo_quota_bytes_used", "Bytes counted in the quota", quotas.bytes.get());
+    gauge(&mut out, "duplo_quota_bytes_allowed", "Maximum number of bytes", quotas.bytes.allowed());
+    let _ = writeln!(
+        out,
+        "# HELP duplo_share_capacity_bytes Bytes that can be uploaded to the directory, considering quota and free disk space\n# TYPE duplo_share_capacity_bytes gauge"
+    );
+    for share in shares.iter() {
+        let share = share.get();
+        let _ = writeln!(out, "duplo_share_capacity_bytes{{share=\"{}\"}} {}", share.name, quotas.remaining_capacity(&share.dir));
+    }
+    let scanned = quotas.scanned();
+    let _ = writeln!(out, "# HELP duplo_share_files Files in the directory as of the last quota scan\n# TYPE duplo_share_files gauge");
+    for u in &scanned {
+        let _ = writeln!(out, "duplo_share_files{{share=\"{}\"}} {}", u.share, u.files);
+    }
+    let _ = writeln!(out, "# HELP duplo_share_bytes Bytes in the directory as of the last quota scan\n# TYPE duplo_share_bytes gauge");
+    for u in &scanned {
+        let _ = writeln!(out, "duplo_share_bytes{{share=\"{}\"}} {}", u.share, u.bytes);
+    }
+    counter(
+        &mut out,
+        "duplo_quota_drift_corrections_total",
+        "Quota rescans that found counters out of sync with directory contents",
+        m.quota_drift_corrections.load(SeqCst),
+    );
+    m.uploads.render(&mut out, "duplo_uploads_total", "Completed uploads");
+    m.upload_bytes.render(&mut out, "duplo_upload_bytes_total", "Bytes of completed uploads");
+    m.text_shares.render(&mut out, "duplo_text_shares_total", "Shared texts");
+    m.removals.render(&mut out, "duplo_removals_total", "Files removed by clients");
+    m.downloads.render(&mut out, "duplo_downloads_total", "Successful responses with served files");
+    m.download_bytes.render(&mut out, "duplo_download_bytes_total", "Bytes of served files sent");
+    gauge(&mut out, "duplo_uploads_in_flight", "Uploads being received now", m.uploads_in_flight.load(SeqCst));
+    let _ = writeln!(out, "# HELP duplo_rejected_requests_total Uploads, text shares and removals that failed, by reason\n# TYPE duplo_rejected_requests_total counter");
+    for (reason, v) in REJECTION_REASONS.iter().zip(&m.rejections) {
+        let _ = writeln!(out, "duplo_rejected_requests_total{{reason=\"{reason}\"}} {}", v.load(SeqCst));
+    }
+    let _ = writeln!(out, "# HELP duplo_timeouts_total Connections closed and requests aborted due to timeouts, by kind\n# TYPE duplo_timeouts_total counter");
+    for (kind, v) in TIMEOUT_KINDS.iter().zip(&m.timeouts) {
+        let _ = writeln!(out, "duplo_timeouts_total{{kind=\"{kind}\"}} {}", v.load(SeqCst));
+    }
+    counter(&mut out, "duplo_cleanup_runs_total", "Cleanups of transient directory", m.cleanup_runs.load(SeqCst));
+    gauge(
+        &mut out,
+        "duplo_cleanup_last_duration_seconds",
+        "Duration of the last cleanup",
+        m.cleanup_last_duration_micros.load(SeqCst) as f64 / 1e6,
+    );
+    counter(
+        &mut out,
+        "duplo_cleanup_duration_seconds_total",
+        "Time spent in cleanups",
+        m.cleanup_duration_micros.load(SeqCst) as f64 / 1e6,
+    );
+    counter(&mut out, "duplo_cleanup_removed_files_total", "Files removed by cleanups", m.cleanup_removed_files.load(SeqCst));
+    counter(&mut out, "duplo_cleanup_removed_bytes_total", "Bytes removed by cleanups", m.cleanup_removed_bytes.load(SeqCst));
+    let _ = writeln!(out, "# HELP duplo_http_request_duration_seconds Time to handle HTTP requests, by status class\n# TYPE duplo_http_request_duration_seconds histogram");
+    for (i, h) in m.latency.iter().enumerate() {
+        h.render(&mut out, "duplo_http_request_duration_seconds", &format!("status=\"{}xx\"", i + 1));
+    }
+    ([(CONTENT_TYPE, "text/plain; version=0.0.4")], out).into_response()
+}
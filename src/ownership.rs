@@ -0,0 +1,111 @@
+use std::{
+    collections::BTreeMap,
+    ffi::CString,
+    fs::{File, Permissions},
+    io,
+    os::unix::fs::PermissionsExt,
+    sync::Mutex,
+};
+
+use anyhow::Context;
+use nix::unistd::{Gid, Group, Uid, User};
+use tracing::info;
+
+/// Mode bits and group given to files created in a shared directory
+#[derive(Clone, Copy)]
+pub struct Ownership {
+    /// Applied regardless of umask
+    pub mode: Option<u32>,
+    pub group: Option<Gid>,
+}
+
+impl Ownership {
+    /// Set group and mode of a newly created file
+    pub fn apply(&self, file: &File) -> io::Result<()> {
+        // Changing the group can clear setgid bit, so it goes first
+        if let Some(gid) = self.group {
+            std::os::unix::fs::fchown(file, None, Some(gid.as_raw()))?;
+        }
+        if let Some(mode) = self.mode {
+            file.set_permissions(Permissions::from_mode(mode))?;
+        }
+        Ok(())
+    }
+}
+
+/// Octal permission bits, like `0640`. Setuid, setgid and sticky bits are not allowed.
+pub fn parse_mode(x: &str) -> Result<u32, String> {
+    match u32::from_str_radix(x.trim_start_matches("0o"), 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(format!("Invalid file mode `{x}`, expected octal number from `0` to `0777` like `0640`")),
+    }
+}
+
+/// Users and groups looked up so far. Looking up needs NSS (`/etc/passwd`, `/etc/group`, `/etc/nsswitch.conf` and
+/// whatever it refers to), which `--landlock` makes unreachable, so configuration reloads reuse names resolved at startup.
+static USERS: Mutex<BTreeMap<String, User>> = Mutex::new(BTreeMap::new());
+static GROUPS: Mutex<BTreeMap<String, Gid>> = Mutex::new(BTreeMap::new());
+
+/// Group name or numeric ID
+pub fn parse_group(x: &str) -> Result<Gid, String> {
+    if let Ok(id) = x.parse() {
+        return Ok(Gid::from_raw(id));
+    }
+    if let Some(gid) = GROUPS.lock().unwrap().get(x) {
+        return Ok(*gid);
+    }
+    match Group::from_name(x) {
+        Ok(Some(g)) => {
+            GROUPS.lock().unwrap().insert(x.to_owned(), g.gid);
+            Ok(g.gid)
+        }
+        Ok(None) => Err(format!("Unknown group `{x}`")),
+        Err(e) => Err(format!("Cannot look up group `{x}`: {e}")),
+    }
+}
+
+/// User name or numeric ID
+pub fn parse_user(x: &str) -> Result<User, String> {
+    if let Some(user) = USERS.lock().unwrap().get(x) {
+        return Ok(user.clone());
+    }
+    let user = match x.parse() {
+        Ok(id) => User::from_uid(Uid::from_raw(id)),
+        Err(_) => User::from_name(x),
+    };
+    match user {
+        Ok(Some(u)) => {
+            USERS.lock().unwrap().insert(x.to_owned(), u.clone());
+            Ok(u)
+        }
+        Ok(None) => Err(format!("Unknown user `{x}`")),
+        Err(e) => Err(format!("Cannot look up user `{x}`: {e}")),
+    }
+}
+
+/// Switch the process to `user`, its primary group and supplementary groups
+pub fn drop_privileges(user: &User) -> anyhow::Result<()> {
+    let name = CString::new(user.name.as_str())?;
+    nix::unistd::initgroups(&name, user.gid).context("setting groups (switching users requires starting as root)")?;
+    nix::unistd::setgid(user.gid)?;
+    nix::unistd::setuid(user.uid)?;
+    info!("Switched to user {} (uid {}, gid {})", user.name, user.uid, user.gid);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_mode;
+
+    #[test]
+    fn modes() {
+        assert_eq!(parse_mode("0640"), Ok(0o640));
+        assert_eq!(parse_mode("640"), Ok(0o640));
+        assert_eq!(parse_mode("0o600"), Ok(0o600));
+        assert_eq!(parse_mode("0777"), Ok(0o777));
+        assert_eq!(parse_mode("0"), Ok(0));
+        for bad in ["4755", "2640", "1777", "07777", "1000", "0800", "rw-r-----", "", "-1"] {
+            assert!(parse_mode(bad).is_err(), "{bad}");
+        }
+    }
+}
@@ -0,0 +1,270 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, Request},
+    middleware::Next,
+    response::Response,
+};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tracing::debug;
+
+use crate::{clientlimits::mask, listen::UNIX_PEER_ADDR};
+
+/// Signature that starts PROXY protocol v2 header
+const PROXY_V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Maximum length of PROXY protocol v1 header line, including CRLF
+const PROXY_V1_MAX_LEN: usize = 107;
+
+/// Peers that are allowed to tell us the real client address
+pub struct TrustedProxies {
+    /// Network address and prefix length
+    nets: Vec<(IpAddr, u8)>,
+    /// Trust clients connected over Unix sockets
+    unix: bool,
+}
+
+impl TrustedProxies {
+    /// Parse comma-separated list of IP addresses, `IP/PREFIX` subnets and `unix`
+    pub fn parse(list: &str) -> anyhow::Result<TrustedProxies> {
+        let mut t = TrustedProxies { nets: vec![], unix: false };
+        for x in list.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+            if x == "unix" {
+                t.unix = true;
+                continue;
+            }
+            let (ip, prefix) = match x.split_once('/') {
+                Some((ip, prefix)) => (ip, Some(prefix)),
+                None => (x, None),
+            };
+            let ip: IpAddr = ip.parse().map_err(|_| anyhow::anyhow!("Invalid trusted proxy address `{x}`"))?;
+            let ip = ip.to_canonical();
+            let max = if ip.is_ipv4() { 32 } else { 128 };
+            let prefix = match prefix {
+                Some(p) => p.parse().ok().filter(|p| *p <= max).ok_or_else(|| anyhow::anyhow!("Invalid prefix length in `{x}`"))?,
+                None => max,
+            };
+            t.nets.push((mask(ip, prefix, prefix), prefix));
+        }
+        Ok(t)
+    }
+
+    fn is_trusted_ip(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.nets.iter().any(|&(net, prefix)| net.is_ipv4() == ip.is_ipv4() && mask(ip, prefix, prefix) == net)
+    }
+
+    /// Whether a directly connected peer is a trusted proxy
+    pub fn is_trusted_peer(&self, peer: SocketAddr) -> bool {
+        if peer == UNIX_PEER_ADDR {
+            return self.unix;
+        }
+        self.is_trusted_ip(peer.ip())
+    }
+
+    /// Find the client address based on `Forwarded` or `X-Forwarded-For` headers, going from the
+    /// nearest hop back while hops are trusted proxies
+    pub fn resolve(&self, peer: SocketAddr, headers: &HeaderMap) -> SocketAddr {
+        if !self.is_trusted_peer(peer) {
+            return peer;
+        }
+        let hops: Vec<&str> = if headers.contains_key("forwarded") {
+            headers
+                .get_all("forwarded")
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(','))
+                .filter_map(|element| {
+                    element.split(';').find_map(|pair| {
+                        let (k, v) = pair.split_once('=')?;
+                        k.trim().eq_ignore_ascii_case("for").then(|| v.trim().trim_matches('"'))
+                    })
+                })
+                .collect()
+        } else {
+            headers
+                .get_all("x-forwarded-for")
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(','))
+                .map(str::trim)
+                .collect()
+        };
+        let mut client = peer;
+        for hop in hops.into_iter().rev() {
+            match parse_hop(hop) {
+                Some(addr) => client = addr,
+                // Obfuscated identifier or `unknown` - nothing more can be learned
+                None => break,
+            }
+            if !self.is_trusted_ip(client.ip()) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+/// Parse node from `Forwarded` or `X-Forwarded-For`: `IP`, `IP:PORT`, `[IPv6]` or `[IPv6]:PORT`
+fn parse_hop(x: &str) -> Option<SocketAddr> {
+    if let Ok(a) = x.parse::<SocketAddr>() {
+        return Some(a);
+    }
+    let ip = x.strip_prefix('[').and_then(|x| x.strip_suffix(']')).unwrap_or(x);
+    ip.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, 0))
+}
+
+/// Middleware that replaces `ConnectInfo` with the client address reported by trusted proxies
+pub(crate) async fn real_client_addr<B>(
+    State(proxies): State<Arc<TrustedProxies>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    mut req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let client = proxies.resolve(peer, req.headers());
+    if client != peer {
+        req.extensions_mut().insert(ConnectInfo(client));
+    }
+    next.run(req).await
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Read HAProxy PROXY protocol (v1 or v2) header from a newly accepted connection and return the
+/// client address from it, or `peer` if the proxy does not specify one (e.g. for health checks)
+pub async fn read_proxy_header<T: AsyncRead + Unpin>(io: &mut T, peer: SocketAddr) -> io::Result<SocketAddr> {
+    // Shortest valid v1 header (`PROXY UNKNOWN\r\n`) is longer than v2 signature
+    let mut start = [0u8; 12];
+    io.read_exact(&mut start).await?;
+    if &start == PROXY_V2_SIGNATURE {
+        let mut hdr = [0u8; 4];
+        io.read_exact(&mut hdr).await?;
+        let [ver_cmd, family, len_hi, len_lo] = hdr;
+        if ver_cmd >> 4 != 2 {
+            return Err(invalid("unsupported PROXY protocol version"));
+        }
+        let mut body = vec![0u8; usize::from(u16::from_be_bytes([len_hi, len_lo]))];
+        io.read_exact(&mut body).await?;
+        match ver_cmd & 0xF {
+            // LOCAL command: connection established by the proxy itself
+            0 => return Ok(peer),
+            // PROXY command
+            1 => (),
+            _ => return Err(invalid("unsupported PROXY protocol command")),
+        }
+        let addr = match family >> 4 {
+            1 if body.len() >= 12 => {
+                let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+                SocketAddr::new(ip.into(), u16::from_be_bytes([body[8], body[9]]))
+            }
+            2 if body.len() >= 36 => {
+                let ip: [u8; 16] = body[..16].try_into().unwrap();
+                SocketAddr::new(Ipv6Addr::from(ip).into(), u16::from_be_bytes([body[32], body[33]]))
+            }
+            _ => peer,
+        };
+        return Ok(addr);
+    }
+    if !start.starts_with(b"PROXY ") {
+        return Err(invalid("missing PROXY protocol header"));
+    }
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= PROXY_V1_MAX_LEN {
+            return Err(invalid("PROXY protocol header is too long"));
+        }
+        line.push(io.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid("malformed PROXY protocol header"))?;
+    let mut parts = line.split(' ').skip(1);
+    match parts.next() {
+        Some("TCP4" | "TCP6") => (),
+        Some("UNKNOWN") => return Ok(peer),
+        _ => return Err(invalid("unsupported PROXY protocol family")),
+    }
+    let (Some(src), Some(_dst), Some(sport)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(invalid("truncated PROXY protocol header"));
+    };
+    let ip: IpAddr = src.parse().map_err(|_| invalid("invalid address in PROXY protocol header"))?;
+    let port: u16 = sport.parse().map_err(|_| invalid("invalid port in PROXY protocol header"))?;
+    debug!("{peer} is proxying for {ip}:{port}");
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// Normalize `--url-prefix` value: ensure leading slash and strip trailing ones
+pub fn parse_url_prefix(x: &str) -> Result<String, String> {
+    let x = x.trim_end_matches('/');
+    if x.is_empty() {
+        return Ok(String::new());
+    }
+    if !x.starts_with('/') {
+        return Err(format!("URL prefix `{x}` must start with `/`"));
+    }
+    if x.contains(['?', '#', '"', '<', '>', ' ']) {
+        return Err(format!("URL prefix `{x}` contains characters that are not allowed"));
+    }
+    Ok(x.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 40000);
+
+    async fn parse(header: &[u8]) -> io::Result<SocketAddr> {
+        let mut io = header;
+        read_proxy_header(&mut io, PEER).await
+    }
+
+    fn v2(ver_cmd: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut h = PROXY_V2_SIGNATURE.to_vec();
+        h.extend([ver_cmd, family]);
+        h.extend((body.len() as u16).to_be_bytes());
+        h.extend(body);
+        h
+    }
+
+    #[tokio::test]
+    async fn v1() {
+        let a = parse(b"PROXY TCP4 192.0.2.1 198.51.100.1 5555 443\r\nGET /").await.unwrap();
+        assert_eq!(a, "192.0.2.1:5555".parse().unwrap());
+        let a = parse(b"PROXY TCP6 2001:db8::1 2001:db8::2 5555 443\r\n").await.unwrap();
+        assert_eq!(a, "[2001:db8::1]:5555".parse().unwrap());
+        assert_eq!(parse(b"PROXY UNKNOWN\r\n").await.unwrap(), PEER);
+        assert!(parse(b"GET / HTTP/1.1\r\n\r\n").await.is_err());
+        assert!(parse(b"PROXY TCP4 192.0.2.1\r\n").await.is_err());
+        assert!(parse(b"PROXY TCP4 nonsense 198.51.100.1 5555 443\r\n").await.is_err());
+        assert!(parse(format!("PROXY TCP4 {}\r\n", "1".repeat(200)).as_bytes()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn v2_commands() {
+        let ipv4 = [192, 0, 2, 1, 198, 51, 100, 1, 0x15, 0xb3, 1, 0xbb];
+        assert_eq!(parse(&v2(0x21, 0x11, &ipv4)).await.unwrap(), "192.0.2.1:5555".parse().unwrap());
+        assert_eq!(parse(&v2(0x20, 0x00, &[])).await.unwrap(), PEER);
+        assert!(parse(&v2(0x22, 0x11, &ipv4)).await.is_err());
+        assert!(parse(&v2(0x2F, 0x11, &ipv4)).await.is_err());
+        assert!(parse(&v2(0x11, 0x11, &ipv4)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn v2_families() {
+        let mut ipv6 = vec![0x20, 0x01, 0x0d, 0xb8];
+        ipv6.resize(15, 0);
+        ipv6.push(1);
+        ipv6.resize(32, 0);
+        ipv6.extend([0x15, 0xb3, 1, 0xbb]);
+        assert_eq!(parse(&v2(0x21, 0x21, &ipv6)).await.unwrap(), "[2001:db8::1]:5555".parse().unwrap());
+        // Unix socket addresses and truncated bodies tell nothing about the client
+        assert_eq!(parse(&v2(0x21, 0x31, &[0; 216])).await.unwrap(), PEER);
+        assert_eq!(parse(&v2(0x21, 0x11, &[192, 0, 2, 1])).await.unwrap(), PEER);
+        assert!(parse(&v2(0x21, 0x11, &[])[..14]).await.is_err());
+    }
+}
@@ -0,0 +1,66 @@
+use std::path::{Path, PathBuf};
+
+use landlock::{
+    path_beneath_rules, Access, AccessFs, AccessNet, LandlockStatus, Ruleset, RulesetAttr,
+    RulesetCreatedAttr, RulesetStatus, ABI,
+};
+use tracing::{info, warn};
+
+/// Newest Landlock ABI whose restrictions are requested. Older kernels enforce what they can.
+const ABI_USED: ABI = ABI::V5;
+
+/// Directories containing the files `files` resolve to, so that files replaced there by renaming or by
+/// repointing a symlink (like renewed certificates, e.g. in certbot's `archive` directory) stay readable
+pub fn target_dirs<'a>(files: impl IntoIterator<Item = &'a Path>) -> Vec<PathBuf> {
+    let mut dirs = vec![];
+    for f in files {
+        let f = std::fs::canonicalize(f).unwrap_or_else(|_| f.to_owned());
+        let dir = match f.parent() {
+            Some(d) if d != Path::new("") => d.to_owned(),
+            _ => PathBuf::from("."),
+        };
+        if !dirs.contains(&dir) {
+            dirs.push(dir);
+        }
+    }
+    dirs
+}
+
+/// Restrict this thread and threads it spawns afterwards with Landlock: full access to `read_write` directories,
+/// reading `read_files` and files in `read_dirs` (not listing them), nothing else on the filesystem, and no binding
+/// or connecting TCP sockets. Already opened files and bound sockets keep working.
+/// Kernels without (full) Landlock support get what they support.
+pub fn landlock(read_write: &[PathBuf], read_files: &[PathBuf], read_dirs: &[PathBuf]) -> anyhow::Result<()> {
+    let status = Ruleset::default()
+        .handle_access(AccessFs::from_all(ABI_USED))?
+        .handle_access(AccessNet::BindTcp | AccessNet::ConnectTcp)?
+        .create()?
+        .add_rules(path_beneath_rules(read_write, AccessFs::from_all(ABI_USED)))?
+        .add_rules(path_beneath_rules(read_files, AccessFs::ReadFile))?
+        .add_rules(path_beneath_rules(read_dirs, AccessFs::ReadFile))?
+        .restrict_self()?;
+    let LandlockStatus::Available { effective_abi: abi, .. } = status.landlock else {
+        warn!("Landlock is not available ({:?}), running without sandbox", status.landlock);
+        return Ok(());
+    };
+    match status.ruleset {
+        RulesetStatus::NotEnforced => {
+            warn!("Landlock sandbox could not be enforced, running without it");
+            return Ok(());
+        }
+        RulesetStatus::FullyEnforced => info!("Landlock sandbox is fully enforced"),
+        RulesetStatus::PartiallyEnforced => {
+            warn!("Landlock sandbox is partially enforced, kernel supports only {abi:?} of {ABI_USED:?}")
+        }
+    }
+    info!("Filesystem access is limited to {read_write:?}, reading {read_files:?} and reading files from {read_dirs:?}");
+    if abi < ABI::V3 {
+        warn!("Truncating files is not restricted by Landlock ABI {abi:?}");
+    }
+    if abi >= ABI::V4 {
+        info!("Binding and connecting TCP sockets is forbidden");
+    } else {
+        warn!("Network access is not restricted by Landlock ABI {abi:?}");
+    }
+    Ok(())
+}
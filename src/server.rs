@@ -0,0 +1,372 @@
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::{
+    body::{BoxBody, Bytes, HttpBody},
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, Request, StatusCode, Version},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension, Router,
+};
+use hyper::{body::SizeHint, server::conn::Http};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+    time::{Instant, Sleep},
+};
+use tokio_rustls::TlsAcceptor;
+use tower::{Layer, ServiceExt};
+use tracing::{debug, info, warn};
+
+use crate::{
+    listen::Listener,
+    metrics::{self, Timeout},
+    proxy::{read_proxy_header, TrustedProxies},
+    tls::ClientIdentity,
+};
+
+#[derive(Clone, Copy)]
+pub struct ConnectionLimits {
+    pub header_read_timeout: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+}
+
+/// Closes the connection if no bytes were transferred in either direction for a while
+struct IdleTimeout<T> {
+    io: T,
+    peer: SocketAddr,
+    timeout: Option<Duration>,
+    deadline: Pin<Box<Sleep>>,
+    timed_out: bool,
+}
+
+impl<T> IdleTimeout<T> {
+    fn new(io: T, peer: SocketAddr, timeout: Option<Duration>) -> IdleTimeout<T> {
+        let deadline = Box::pin(tokio::time::sleep(timeout.unwrap_or(Duration::ZERO)));
+        IdleTimeout {
+            io,
+            peer,
+            timeout,
+            deadline,
+            timed_out: false,
+        }
+    }
+
+    fn reset(&mut self) {
+        if let Some(t) = self.timeout {
+            self.deadline.as_mut().reset(Instant::now() + t);
+        }
+    }
+
+    fn poll_deadline<R>(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<R>> {
+        if self.timeout.is_none() {
+            return Poll::Pending;
+        }
+        match self.deadline.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                if !self.timed_out {
+                    self.timed_out = true;
+                    info!("{} connection closed due to idle timeout", self.peer);
+                    metrics::timed_out(Timeout::Idle);
+                }
+                Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, "connection is idle for too long")))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for IdleTimeout<T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.io).poll_read(cx, buf) {
+            Poll::Ready(r) => {
+                this.reset();
+                Poll::Ready(r)
+            }
+            Poll::Pending => this.poll_deadline(cx),
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for IdleTimeout<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.io).poll_write(cx, buf) {
+            Poll::Ready(r) => {
+                this.reset();
+                Poll::Ready(r)
+            }
+            Poll::Pending => this.poll_deadline(cx),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+}
+
+/// Plain or TLS connection
+trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+/// What a connection is busy with, to tell when it is waiting for request headers
+#[derive(Default)]
+struct ConnState {
+    /// Requests whose headers were received and whose responses are not yet fully sent
+    active_requests: usize,
+    /// HTTP/2 streams are not subject to header read timeout
+    http2: bool,
+}
+
+/// Response body that keeps its request counted as active until it is sent or dropped
+struct TrackedBody {
+    body: BoxBody,
+    state: Arc<watch::Sender<ConnState>>,
+}
+
+impl Drop for TrackedBody {
+    fn drop(&mut self) {
+        self.state.send_modify(|s| s.active_requests -= 1);
+    }
+}
+
+impl HttpBody for TrackedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_data(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, axum::Error>>> {
+        Pin::new(&mut self.get_mut().body).poll_data(cx)
+    }
+
+    fn poll_trailers(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<Option<HeaderMap>, axum::Error>> {
+        Pin::new(&mut self.get_mut().body).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+/// Resolves when an HTTP/1 connection has been without an active request for `timeout`, i.e. the client is slow
+/// to send (next) request headers. Hyper has its own header read timeout, but does not tell its error apart from others.
+async fn header_timeout(mut state: watch::Receiver<ConnState>, timeout: Duration) {
+    loop {
+        let waiting = {
+            let s = state.borrow_and_update();
+            s.active_requests == 0 && !s.http2
+        };
+        let changed = if waiting {
+            match tokio::time::timeout(timeout, state.changed()).await {
+                Ok(x) => x,
+                Err(_) => return,
+            }
+        } else {
+            state.changed().await
+        };
+        if changed.is_err() {
+            // Connection is gone
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+enum Interrupted {
+    TimedOut,
+    ShuttingDown,
+}
+
+/// Run a step of connection setup (PROXY protocol header, TLS handshake), giving up if it takes longer
+/// than header read timeout or the server starts shutting down, so that stalled clients do not delay it
+async fn before_serving<F: Future>(
+    step: F,
+    limits: ConnectionLimits,
+    shutdown: &mut watch::Receiver<bool>,
+) -> Result<F::Output, Interrupted> {
+    let timeout = async {
+        match limits.header_read_timeout {
+            Some(t) => tokio::time::sleep(t).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::select! {
+        x = step => Ok(x),
+        () = timeout => Err(Interrupted::TimedOut),
+        _ = shutdown.changed() => Err(Interrupted::ShuttingDown),
+    }
+}
+
+/// Accept connections (performing TLS handshake if `tls` is set) and serve `app` on them, providing `ConnectInfo<SocketAddr>` to handlers.
+/// If `proxy_protocol` is set, connections from those trusted proxies must start with PROXY protocol header.
+/// When `shutdown` changes, stops accepting connections and asks existing ones to close after their current requests;
+/// all clones of `shutdown` receiver get dropped when they do.
+pub async fn serve(
+    listener: Listener,
+    app: Router,
+    limits: ConnectionLimits,
+    tls: Option<TlsAcceptor>,
+    proxy_protocol: Option<Arc<TrustedProxies>>,
+    mut shutdown: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let http = Http::new();
+    loop {
+        let accepted = tokio::select! {
+            x = listener.accept() => x,
+            _ = shutdown.changed() => return Ok(()),
+        };
+        let (mut socket, peer) = match accepted {
+            Ok(x) => x,
+            Err(e) => {
+                // Most likely out of file descriptors - let some connections finish
+                warn!("accept: {e}");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let app = app.clone();
+        let http = http.clone();
+        let tls = tls.clone();
+        let proxy_protocol = proxy_protocol.clone();
+        let mut shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            let mut addr = peer;
+            if proxy_protocol.is_some_and(|p| p.is_trusted_peer(peer)) {
+                let header = before_serving(read_proxy_header(&mut socket, peer), limits, &mut shutdown).await;
+                match header {
+                    Ok(Ok(a)) => addr = a,
+                    Ok(Err(e)) => {
+                        info!("{peer} failed to read PROXY protocol header: {e}");
+                        return;
+                    }
+                    Err(Interrupted::TimedOut) => {
+                        info!("{peer} timed out sending PROXY protocol header");
+                        metrics::timed_out(Timeout::Header);
+                        return;
+                    }
+                    Err(Interrupted::ShuttingDown) => return,
+                }
+            }
+            let io = IdleTimeout::new(socket, addr, limits.idle_timeout);
+            let app = Extension(ConnectInfo(addr)).layer(app);
+            let (io, identity): (Box<dyn Io>, ClientIdentity) = match tls {
+                None => (Box::new(io), ClientIdentity::default()),
+                Some(tls) => {
+                    let io = match before_serving(tls.accept(io), limits, &mut shutdown).await {
+                        Ok(x) => x,
+                        Err(Interrupted::TimedOut) => {
+                            info!("{addr} timed out doing TLS handshake");
+                            metrics::timed_out(Timeout::Header);
+                            return;
+                        }
+                        Err(Interrupted::ShuttingDown) => return,
+                    };
+                    match io {
+                        Ok(io) => {
+                            let identity = match io.get_ref().1.peer_certificates() {
+                                Some([cert, ..]) => ClientIdentity::from_der(&cert.0),
+                                _ => ClientIdentity::default(),
+                            };
+                            (Box::new(io), identity)
+                        }
+                        Err(e) => {
+                            debug!("{addr} TLS handshake failed: {e}");
+                            return;
+                        }
+                    }
+                }
+            };
+            let (state, state_rx) = watch::channel(ConnState::default());
+            let state = Arc::new(state);
+            let state2 = state.clone();
+            let app = Extension(identity)
+                .layer(app)
+                .map_request(move |req: Request<hyper::Body>| {
+                    state.send_modify(|s| {
+                        s.active_requests += 1;
+                        s.http2 |= req.version() == Version::HTTP_2;
+                    });
+                    req
+                })
+                .map_response(move |response: Response| {
+                    let state = state2.clone();
+                    response.map(|body| axum::body::boxed(TrackedBody { body, state }))
+                });
+            let conn = http.serve_connection(io, app);
+            tokio::pin!(conn);
+            let timed_out = async {
+                match limits.header_read_timeout {
+                    Some(t) => header_timeout(state_rx, t).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::pin!(timed_out);
+            let mut shutting_down = false;
+            let result = loop {
+                tokio::select! {
+                    x = conn.as_mut() => break x,
+                    () = &mut timed_out => {
+                        info!("{addr} timed out sending request headers");
+                        metrics::timed_out(Timeout::Header);
+                        return;
+                    }
+                    _ = shutdown.changed(), if !shutting_down => {
+                        shutting_down = true;
+                        conn.as_mut().graceful_shutdown();
+                    }
+                }
+            };
+            if let Err(e) = result {
+                debug!("{addr} connection error: {e}");
+            }
+        });
+    }
+}
+
+/// Install SIGTERM and SIGINT handlers; returned future resolves when either signal is received
+pub fn shutdown_signal() -> io::Result<impl Future<Output = ()>> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    Ok(async move {
+        tokio::select! {
+            _ = sigterm.recv() => info!("Received SIGTERM, shutting down"),
+            _ = sigint.recv() => info!("Received SIGINT, shutting down"),
+        }
+    })
+}
+
+/// Middleware that aborts requests that take longer than given duration to handle.
+/// The handler future is dropped on timeout; for uploads that removes the staging file (see `StagingFile`'s `Drop`)
+/// and releases its quota and upload slot, so nothing is left behind.
+pub(crate) async fn limit_request_duration<B>(
+    State(max_duration): State<Duration>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let path = req.uri().path().to_owned();
+    match tokio::time::timeout(max_duration, next.run(req)).await {
+        Ok(response) => response,
+        Err(_) => {
+            warn!("{addr} request to {path} took too long, aborting it");
+            metrics::timed_out(Timeout::Request);
+            (StatusCode::REQUEST_TIMEOUT, "Request took too long").into_response()
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use std::{
+    fs::FileType,
+    os::unix::fs::FileTypeExt,
+    path::{Component, Path},
+    sync::Arc,
+};
+
+use axum::{
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use tracing::info;
+
+use crate::SharedDirectory;
+
+/// Which symlinks found in a shared directory are followed
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Symlinks are neither served nor listed
+    Deny,
+    /// Only symlinks that resolve to something inside the shared directory
+    WithinShare,
+    /// Any symlink, even to files elsewhere on the host
+    Allow,
+}
+
+pub fn parse_symlink_policy(x: &str) -> Result<SymlinkPolicy, String> {
+    match x {
+        "deny" => Ok(SymlinkPolicy::Deny),
+        "within-share" => Ok(SymlinkPolicy::WithinShare),
+        "allow" => Ok(SymlinkPolicy::Allow),
+        _ => Err(format!("Unknown symlink policy `{x}`, expected `deny`, `within-share` or `allow`")),
+    }
+}
+
+/// Device files, FIFOs and sockets, which are never served
+pub fn is_special(t: FileType) -> bool {
+    t.is_block_device() || t.is_char_device() || t.is_fifo() || t.is_socket()
+}
+
+/// Whether symlink `link` inside shared directory `root` may be followed
+fn symlink_allowed(root: &Path, link: &Path, policy: SymlinkPolicy) -> bool {
+    match policy {
+        SymlinkPolicy::Deny => false,
+        SymlinkPolicy::Allow => true,
+        SymlinkPolicy::WithinShare => match (root.canonicalize(), link.canonicalize()) {
+            (Ok(root), Ok(target)) => target.starts_with(root),
+            _ => false,
+        },
+    }
+}
+
+/// Whether `rel` path within shared directory `root` may be served, listed or removed.
+/// Every symlink on the way must be allowed by `policy` and the file must not be a special one.
+/// Paths that do not exist are allowed, so that they get the usual "not found" treatment.
+pub fn is_allowed(root: &Path, rel: &Path, policy: SymlinkPolicy) -> bool {
+    let mut path = root.to_owned();
+    for c in rel.components() {
+        match c {
+            Component::Normal(x) => path.push(x),
+            Component::CurDir => continue,
+            _ => return false,
+        }
+        let Ok(m) = std::fs::symlink_metadata(&path) else { return true };
+        if m.file_type().is_symlink() && !symlink_allowed(root, &path, policy) {
+            return false;
+        }
+    }
+    match std::fs::metadata(&path) {
+        Ok(m) => !is_special(m.file_type()),
+        Err(_) => true,
+    }
+}
+
+/// Middleware for the `ServeDir` fallback that refuses files forbidden by the share's symlink policy and special files
+pub(crate) async fn enforce_policy<B>(
+    Extension(shared_dir): Extension<Arc<SharedDirectory>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let name = percent_encoding::percent_decode_str(req.uri().path().trim_start_matches('/')).decode_utf8();
+    let Ok(name) = name else { return next.run(req).await };
+    let rel = Path::new(&*name);
+    let mut allowed = is_allowed(&shared_dir.dir, rel, shared_dir.symlinks);
+    // Directories are served by their `index.html`
+    if allowed && shared_dir.dir.join(rel).is_dir() {
+        allowed = is_allowed(&shared_dir.dir, &rel.join("index.html"), shared_dir.symlinks);
+    }
+    if !allowed {
+        info!("Refusing to serve {:?} from {} directory due to symlink or special file policy", name, shared_dir.name);
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::fs::symlink, path::Path};
+
+    use super::{is_allowed, SymlinkPolicy};
+
+    #[test]
+    fn symlink_policies() {
+        use SymlinkPolicy::*;
+        let base = std::env::temp_dir().join(format!("duplo-test-symlinks-{}", std::process::id()));
+        let share = base.join("share");
+        std::fs::create_dir_all(share.join("sub")).unwrap();
+        std::fs::create_dir_all(base.join("outside")).unwrap();
+        std::fs::write(share.join("file.txt"), "x").unwrap();
+        std::fs::write(share.join("sub/inner.txt"), "x").unwrap();
+        std::fs::write(base.join("outside/secret.txt"), "x").unwrap();
+        symlink("file.txt", share.join("inside")).unwrap();
+        symlink("sub", share.join("inside_dir")).unwrap();
+        symlink("../outside/secret.txt", share.join("outside")).unwrap();
+        symlink("../outside", share.join("outside_dir")).unwrap();
+        // Each link of the chain is within the share, but it ends outside
+        symlink("chain2", share.join("chain1")).unwrap();
+        symlink("outside", share.join("chain2")).unwrap();
+        symlink("missing.txt", share.join("dangling")).unwrap();
+        nix::unistd::mkfifo(&share.join("fifo"), nix::sys::stat::Mode::S_IRWXU).unwrap();
+
+        let cases: &[(&str, [bool; 3])] = &[
+            // Deny, WithinShare, Allow
+            ("file.txt", [true, true, true]),
+            ("./file.txt", [true, true, true]),
+            ("sub/inner.txt", [true, true, true]),
+            ("missing.txt", [true, true, true]),
+            ("inside", [false, true, true]),
+            ("inside_dir/inner.txt", [false, true, true]),
+            ("outside", [false, false, true]),
+            ("outside_dir/secret.txt", [false, false, true]),
+            ("chain1", [false, false, true]),
+            ("dangling", [false, false, true]),
+            ("fifo", [false, false, false]),
+            ("../outside/secret.txt", [false, false, false]),
+            ("sub/../file.txt", [false, false, false]),
+            ("/etc/passwd", [false, false, false]),
+        ];
+        for (rel, expected) in cases {
+            for (policy, allowed) in [Deny, WithinShare, Allow].into_iter().zip(expected) {
+                assert_eq!(is_allowed(&share, Path::new(rel), policy), *allowed, "{rel} with policy {}", policy as u8);
+            }
+        }
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,227 @@
+use std::{
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use axum::{
+    http::{header::HOST, uri::PathAndQuery, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Redirect, Response},
+    Router,
+};
+use rustls::{
+    server::{AllowAnyAnonymousOrAuthenticatedClient, ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    Certificate, PrivateKey, RootCertStore, ServerConfig,
+};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_rustls::TlsAcceptor;
+use tracing::{error, info};
+
+/// How often to check certificate and key files for modifications
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// Request client certificates signed by CAs from this file
+    pub client_ca: Option<PathBuf>,
+}
+
+/// Names from subject CN and DNS and email SANs of a verified client certificate
+pub struct CertIdentity {
+    pub names: Vec<String>,
+}
+
+/// Request extension with client certificate information for the connection, if any
+#[derive(Clone, Default)]
+pub struct ClientIdentity(pub Option<Arc<CertIdentity>>);
+
+impl std::fmt::Display for ClientIdentity {
+    /// Appended to client address in log lines
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.name() {
+            Some(name) => write!(f, "({name})"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl ClientIdentity {
+    /// Name to identify the client by in logs
+    pub fn name(&self) -> Option<&str> {
+        self.0.as_ref().and_then(|c| c.names.first()).map(String::as_str)
+    }
+
+    pub fn from_der(der: &[u8]) -> ClientIdentity {
+        use x509_parser::{certificate::X509Certificate, extensions::GeneralName, prelude::FromDer};
+        let cert = match X509Certificate::from_der(der) {
+            Ok((_, c)) => c,
+            Err(e) => {
+                error!("Failed to parse verified client certificate: {e}");
+                return ClientIdentity(None);
+            }
+        };
+        let mut names: Vec<String> = cert
+            .subject()
+            .iter_common_name()
+            .filter_map(|cn| cn.as_str().ok())
+            .map(ToOwned::to_owned)
+            .collect();
+        if let Ok(Some(san)) = cert.subject_alternative_name() {
+            for n in &san.value.general_names {
+                match n {
+                    GeneralName::DNSName(x) | GeneralName::RFC822Name(x) => names.push((*x).to_owned()),
+                    _ => (),
+                }
+            }
+        }
+        ClientIdentity(Some(Arc::new(CertIdentity { names })))
+    }
+}
+
+/// Who may upload, share text and remove files in a shared directory
+pub enum WriteAccess {
+    Anyone,
+    /// Anyone with a client certificate signed by `--tls-client-ca`
+    AnyCertificate,
+    /// Only clients with a certificate having one of these names
+    Names(Vec<String>),
+}
+
+impl WriteAccess {
+    pub fn check(&self, identity: &ClientIdentity) -> Result<(), (StatusCode, &'static str)> {
+        let cert = match (self, &identity.0) {
+            (WriteAccess::Anyone, _) => return Ok(()),
+            (_, None) => return Err((StatusCode::FORBIDDEN, "Client certificate is required for this action")),
+            (_, Some(c)) => c,
+        };
+        match self {
+            WriteAccess::Names(allowed) if !cert.names.iter().any(|n| allowed.contains(n)) => {
+                Err((StatusCode::FORBIDDEN, "Your client certificate does not permit this action"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn load_certified_key(files: &TlsFiles) -> anyhow::Result<CertifiedKey> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(
+        std::fs::File::open(&files.cert).with_context(|| format!("opening {:?}", files.cert))?,
+    ))?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificates found in {:?}", files.cert);
+    }
+    let certs = certs.into_iter().map(Certificate).collect();
+
+    let mut key_reader = BufReader::new(std::fs::File::open(&files.key).with_context(|| format!("opening {:?}", files.key))?);
+    let key = loop {
+        match rustls_pemfile::read_one(&mut key_reader)? {
+            Some(rustls_pemfile::Item::PKCS8Key(k) | rustls_pemfile::Item::RSAKey(k) | rustls_pemfile::Item::ECKey(k)) => break PrivateKey(k),
+            Some(_) => continue,
+            None => anyhow::bail!("No private key found in {:?}", files.key),
+        }
+    };
+    let key = rustls::sign::any_supported_type(&key).context("unsupported private key type")?;
+    Ok(CertifiedKey::new(certs, key))
+}
+
+/// Certificate resolver that allows replacing the certificate without affecting established connections
+struct ReloadableCert {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for ReloadableCert {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn mtimes(files: &TlsFiles) -> Option<(SystemTime, SystemTime)> {
+    let m = |p: &Path| std::fs::metadata(p).and_then(|m| m.modified()).ok();
+    Some((m(&files.cert)?, m(&files.key)?))
+}
+
+/// Load certificate and key and make an acceptor that reloads them on SIGHUP or when the files change
+pub fn acceptor(files: TlsFiles) -> anyhow::Result<TlsAcceptor> {
+    let resolver = Arc::new(ReloadableCert {
+        current: RwLock::new(Arc::new(load_certified_key(&files)?)),
+    });
+    let config = ServerConfig::builder().with_safe_defaults();
+    let config = match &files.client_ca {
+        None => config.with_no_client_auth(),
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            let certs = rustls_pemfile::certs(&mut BufReader::new(
+                std::fs::File::open(ca).with_context(|| format!("opening {ca:?}"))?,
+            ))?;
+            let (added, _ignored) = roots.add_parsable_certificates(&certs);
+            if added == 0 {
+                anyhow::bail!("No usable CA certificates found in {ca:?}");
+            }
+            // Anonymous clients are still allowed to connect and download files
+            config.with_client_cert_verifier(AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed())
+        }
+    };
+    let mut config = config.with_cert_resolver(resolver.clone());
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    tokio::spawn(async move {
+        let mut sighup = signal(SignalKind::hangup())
+            .map_err(|e| error!("Cannot listen for SIGHUP, certificate would only be reloaded on file changes: {e}"))
+            .ok();
+        let mut last_mtimes = mtimes(&files);
+        let mut interval = tokio::time::interval(RELOAD_CHECK_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = async {
+                    match &mut sighup {
+                        Some(s) => { s.recv().await; }
+                        None => std::future::pending().await,
+                    }
+                } => (),
+                _ = interval.tick() => {
+                    let m = mtimes(&files);
+                    if m == last_mtimes {
+                        continue;
+                    }
+                    last_mtimes = m;
+                }
+            }
+            match load_certified_key(&files) {
+                Ok(k) => {
+                    *resolver.current.write().unwrap() = Arc::new(k);
+                    info!("Reloaded TLS certificate from {:?}", files.cert);
+                }
+                Err(e) => error!("Failed to reload TLS certificate, continuing to use the old one: {e:#}"),
+            }
+        }
+    });
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Application for plain HTTP listener that sends everyone to HTTPS one
+pub fn redirect_app(https_port: u16) -> Router {
+    Router::new().fallback(move |headers: HeaderMap, uri: Uri| async move { redirect_to_https(https_port, headers, uri) })
+}
+
+fn redirect_to_https(https_port: u16, headers: HeaderMap, uri: Uri) -> Response {
+    let Some(host) = headers.get(HOST).and_then(|h| h.to_str().ok()) else {
+        return (StatusCode::BAD_REQUEST, "Host header is required").into_response();
+    };
+    // Strip port, taking care of IPv6 literals like `[::1]:8080`
+    let host = match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    };
+    let path = uri.path_and_query().map(PathAndQuery::as_str).unwrap_or("/");
+    let target = if https_port == 443 {
+        format!("https://{host}{path}")
+    } else {
+        format!("https://{host}:{https_port}{path}")
+    };
+    Redirect::permanent(&target).into_response()
+}